@@ -0,0 +1,421 @@
+//! Async request handlers.
+//!
+//! [AsyncNode] is a [NodeState] that runs an async handler for every incoming
+//! request. Handlers can await replies to RPCs ([Context::call]) and sleep
+//! ([Context::sleep]), so multi-step protocols can be written as straight-line
+//! code instead of explicit states. Any number of handlers can be in flight at
+//! the same time.
+//!
+//! There is no async runtime involved: the futures are polled by a small
+//! single-threaded executor whenever the node receives input, i.e. from
+//! [NodeState::handle], [NodeState::rpc_reply] and [NodeState::wake_up].
+//! Handlers don't need to be [Send] and can share state using
+//! [std::rc::Rc] and [std::cell::RefCell] (just don't hold a borrow across an
+//! `.await`).
+//!
+//! ```no_run
+//! use fly_into_the_maelstrom::{async_node::*, *};
+//! use serde::{Deserialize, Serialize};
+//!
+//! #[derive(Clone, Debug, Deserialize)]
+//! #[serde(tag = "type", rename_all = "snake_case")]
+//! enum RequestPayload {
+//!     Echo { echo: String },
+//! }
+//!
+//! #[derive(Clone, Debug, Serialize)]
+//! #[serde(tag = "type", rename_all = "snake_case")]
+//! enum ResponsePayload {
+//!     EchoOk { echo: String },
+//! }
+//!
+//! fn main() -> anyhow::Result<()> {
+//!     run_node(Box::new(|_, tx| {
+//!         Ok(Box::new(AsyncNode::new(tx, |ctx, request: Message<RequestPayload>| async move {
+//!             let RequestPayload::Echo { echo } = request.payload;
+//!             ctx.reply(&request.header.request()?, ResponsePayload::EchoOk { echo });
+//!             Ok(())
+//!         })))
+//!     }))
+//! }
+//! ```
+
+use std::{
+    cell::{RefCell, RefMut},
+    collections::{HashMap, VecDeque},
+    future::Future,
+    pin::Pin,
+    rc::Rc,
+    sync::{Arc, Mutex},
+    task::{self, Poll, Wake, Waker},
+    time::{Duration, Instant},
+};
+
+use serde::{de::DeserializeOwned, Serialize};
+
+use crate::{
+    now,
+    typed::{parse_request, ParsedRequest},
+    Envelope, ErrorCode, ErrorPayload, Message, MessageId, MessageTransmitter, NodeId, NodeState,
+    RequestHeader, RpcResponse, RpcResult, TimerId, Transition,
+};
+
+type Task = Pin<Box<dyn Future<Output = ()>>>;
+
+type Handler<R> = Box<dyn FnMut(Context, Message<R>) -> Task>;
+
+/// A [NodeState] running an async handler for each request.
+///
+/// Requests are deserialized into [Message]s of `R` first. Invalid requests
+/// are answered with errors like [crate::TypedNodeState] does. If a handler
+/// returns an [ErrorPayload], it is sent as the reply to its request.
+pub struct AsyncNode<R> {
+    ctx: Context,
+    handler: Handler<R>,
+    executor: Executor,
+}
+
+impl<R: DeserializeOwned + 'static> AsyncNode<R> {
+    pub fn new<H, F>(tx: MessageTransmitter<()>, mut handler: H) -> Self
+    where
+        H: FnMut(Context, Message<R>) -> F + 'static,
+        F: Future<Output = Result<(), ErrorPayload>> + 'static,
+    {
+        Self {
+            ctx: Context {
+                shared: Rc::new(Shared {
+                    tx: RefCell::new(tx),
+                    rpcs: RefCell::default(),
+                    timers: RefCell::default(),
+                    spawned: RefCell::default(),
+                }),
+            },
+            handler: Box::new(move |ctx, request| {
+                let header = request.header;
+                let response = handler(ctx.clone(), request);
+                Box::pin(async move {
+                    if let Err(ErrorPayload { code, text }) = response.await {
+                        if let Ok(request) = header.request() {
+                            ctx.reply_error(&request, code, text);
+                        }
+                    }
+                })
+            }),
+            executor: Executor::default(),
+        }
+    }
+
+    /// Returns the node's [Context], e.g. for spawning background tasks.
+    pub fn context(&self) -> &Context {
+        &self.ctx
+    }
+}
+
+impl<R: DeserializeOwned + 'static> NodeState for AsyncNode<R> {
+    fn handle(&mut self, request: &str) -> Transition {
+        self.handle_envelope(Envelope::parse(request)?)
+    }
+
+    fn handle_envelope(&mut self, request: Envelope<'_>) -> Transition {
+        match parse_request(&request) {
+            ParsedRequest::Valid(request) => {
+                let task = (self.handler)(self.ctx.clone(), request);
+                self.ctx.shared.spawned.borrow_mut().push(task);
+            }
+            ParsedRequest::Ignored => (),
+            ParsedRequest::Invalid(header, code, text) => {
+                self.ctx.reply_error(&header, code, text);
+            }
+        }
+        self.executor.run(&self.ctx);
+        Ok(None)
+    }
+
+    fn wake_up(&mut self, timer: TimerId) -> Transition {
+        if let Some(slot) = self.ctx.shared.timers.borrow_mut().get_mut(&timer) {
+            slot.complete(());
+        }
+        self.executor.run(&self.ctx);
+        Ok(None)
+    }
+
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        let RpcResponse { request_id, result } = response;
+        if let Some(slot) = self.ctx.shared.rpcs.borrow_mut().get_mut(&request_id) {
+            slot.complete(result);
+        }
+        self.executor.run(&self.ctx);
+        Ok(None)
+    }
+
+    fn next_wake_up(&self) -> Option<Instant> {
+        // Tasks spawned from outside of a handler are started right away.
+        if self.ctx.shared.spawned.borrow().is_empty() {
+            None
+        } else {
+            Some(now())
+        }
+    }
+}
+
+/// Lets async handlers send messages, await replies and spawn tasks.
+///
+/// Cloning it is cheap, all clones refer to the same node.
+#[derive(Clone)]
+pub struct Context {
+    shared: Rc<Shared>,
+}
+
+struct Shared {
+    tx: RefCell<MessageTransmitter<()>>,
+    rpcs: RefCell<HashMap<MessageId, Slot<RpcResult>>>,
+    timers: RefCell<HashMap<TimerId, Slot<()>>>,
+    spawned: RefCell<Vec<Task>>,
+}
+
+impl Context {
+    /// Returns the node's transmitter, e.g. for use with [crate::kv::KvClient].
+    ///
+    /// Don't hold on to it across an `.await`.
+    pub fn transmitter(&self) -> RefMut<'_, MessageTransmitter<()>> {
+        self.shared.tx.borrow_mut()
+    }
+
+    /// See [MessageTransmitter::send].
+    pub fn send<Q: Serialize>(&self, dest: NodeId, payload: Q) -> MessageId {
+        self.transmitter().send_any(dest, None, payload)
+    }
+
+    /// See [MessageTransmitter::notify].
+    pub fn notify<Q: Serialize>(&self, dest: NodeId, payload: Q) {
+        self.transmitter().notify_any(dest, payload)
+    }
+
+    /// See [MessageTransmitter::reply].
+    pub fn reply<Q: Serialize>(&self, request: &RequestHeader, payload: Q) -> MessageId {
+        self.transmitter()
+            .send_any(request.src, Some(request.msg_id), payload)
+    }
+
+    /// See [MessageTransmitter::reply_error].
+    pub fn reply_error(
+        &self,
+        request: &RequestHeader,
+        code: ErrorCode,
+        text: impl Into<String>,
+    ) -> MessageId {
+        self.transmitter().reply_error(request, code, text)
+    }
+
+    /// Sends a request to `dest` and returns its outcome.
+    ///
+    /// See [MessageTransmitter::call].
+    pub fn call<Q: Serialize>(
+        &self,
+        dest: NodeId,
+        payload: Q,
+        timeout: Option<Duration>,
+    ) -> RpcFuture {
+        let request_id = self.transmitter().call_any(dest, payload, timeout);
+        self.response(request_id)
+    }
+
+    /// Returns the outcome of a request that was sent with the
+    /// [Context::transmitter] using [MessageTransmitter::call].
+    ///
+    /// This has to be called right after sending the request, replies
+    /// arriving before are not recorded.
+    pub fn response(&self, request_id: MessageId) -> RpcFuture {
+        self.shared
+            .rpcs
+            .borrow_mut()
+            .insert(request_id, Slot::default());
+        RpcFuture {
+            shared: Rc::clone(&self.shared),
+            request_id,
+        }
+    }
+
+    /// Waits for `duration`.
+    pub fn sleep(&self, duration: Duration) -> Sleep {
+        let timer = self.transmitter().timers().schedule_in(duration);
+        self.shared
+            .timers
+            .borrow_mut()
+            .insert(timer, Slot::default());
+        Sleep {
+            shared: Rc::clone(&self.shared),
+            timer,
+        }
+    }
+
+    /// Runs `task` concurrently with all other tasks.
+    pub fn spawn(&self, task: impl Future<Output = ()> + 'static) {
+        self.shared.spawned.borrow_mut().push(Box::pin(task));
+    }
+}
+
+/// The outcome of a request, see [Context::call].
+///
+/// Requests without a timeout that never get a reply never complete.
+pub struct RpcFuture {
+    shared: Rc<Shared>,
+    request_id: MessageId,
+}
+
+impl Future for RpcFuture {
+    type Output = RpcResult;
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<RpcResult> {
+        let mut rpcs = self.shared.rpcs.borrow_mut();
+        let slot = rpcs.get_mut(&self.request_id);
+        match slot.and_then(|slot| slot.poll(cx)) {
+            Some(result) => {
+                rpcs.remove(&self.request_id);
+                Poll::Ready(result)
+            }
+            None => {
+                assert!(
+                    rpcs.contains_key(&self.request_id),
+                    "RpcFuture polled after completion"
+                );
+                Poll::Pending
+            }
+        }
+    }
+}
+
+impl Drop for RpcFuture {
+    fn drop(&mut self) {
+        self.shared.rpcs.borrow_mut().remove(&self.request_id);
+    }
+}
+
+/// Completes after a while, see [Context::sleep].
+pub struct Sleep {
+    shared: Rc<Shared>,
+    timer: TimerId,
+}
+
+impl Future for Sleep {
+    type Output = ();
+
+    fn poll(self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<()> {
+        let mut timers = self.shared.timers.borrow_mut();
+        match timers.get_mut(&self.timer).map(|slot| slot.poll(cx)) {
+            Some(Some(())) => {
+                timers.remove(&self.timer);
+                Poll::Ready(())
+            }
+            Some(None) => Poll::Pending,
+            None => panic!("Sleep polled after completion"),
+        }
+    }
+}
+
+impl Drop for Sleep {
+    fn drop(&mut self) {
+        if self
+            .shared
+            .timers
+            .borrow_mut()
+            .remove(&self.timer)
+            .is_some()
+        {
+            self.shared.tx.borrow().timers().cancel(self.timer);
+        }
+    }
+}
+
+/// Where an event's outcome waits for the future interested in it.
+enum Slot<T> {
+    Waiting(Option<Waker>),
+    Done(T),
+    Taken,
+}
+
+impl<T> Default for Slot<T> {
+    fn default() -> Self {
+        Slot::Waiting(None)
+    }
+}
+
+impl<T> Slot<T> {
+    fn complete(&mut self, value: T) {
+        if let Slot::Waiting(Some(waker)) = std::mem::replace(self, Slot::Done(value)) {
+            waker.wake();
+        }
+    }
+
+    fn poll(&mut self, cx: &mut task::Context<'_>) -> Option<T> {
+        match std::mem::replace(self, Slot::Taken) {
+            Slot::Done(value) => Some(value),
+            Slot::Waiting(_) | Slot::Taken => {
+                *self = Slot::Waiting(Some(cx.waker().clone()));
+                None
+            }
+        }
+    }
+}
+
+/// Polls tasks until none of them can make progress.
+#[derive(Default)]
+struct Executor {
+    tasks: HashMap<usize, Task>,
+    next_id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Executor {
+    fn run(&mut self, ctx: &Context) {
+        loop {
+            let spawned = std::mem::take(&mut *ctx.shared.spawned.borrow_mut());
+            for task in spawned {
+                let id = self.next_id;
+                self.next_id += 1;
+                self.tasks.insert(id, task);
+                self.lock_ready().push_back(id);
+            }
+
+            let Some(id) = self.lock_ready().pop_front() else {
+                if ctx.shared.spawned.borrow().is_empty() {
+                    return;
+                }
+                continue;
+            };
+            let Some(task) = self.tasks.get_mut(&id) else {
+                // Woken after it completed.
+                continue;
+            };
+            let waker = Waker::from(Arc::new(TaskWaker {
+                id,
+                ready: Arc::clone(&self.ready),
+            }));
+            if task
+                .as_mut()
+                .poll(&mut task::Context::from_waker(&waker))
+                .is_ready()
+            {
+                self.tasks.remove(&id);
+            }
+        }
+    }
+
+    fn lock_ready(&self) -> std::sync::MutexGuard<'_, VecDeque<usize>> {
+        self.ready.lock().expect("lock should not be poisoned")
+    }
+}
+
+struct TaskWaker {
+    id: usize,
+    ready: Arc<Mutex<VecDeque<usize>>>,
+}
+
+impl Wake for TaskWaker {
+    fn wake(self: Arc<Self>) {
+        self.ready
+            .lock()
+            .expect("lock should not be poisoned")
+            .push_back(self.id);
+    }
+}
@@ -1,15 +1,10 @@
-use std::{
-    collections::{BTreeSet, HashMap},
-    env,
-    ops::AddAssign,
-    time::{Duration, Instant},
-};
-
-use anyhow::Result;
+use std::{collections::BTreeSet, fmt, ops::AddAssign, str::FromStr, time::Duration};
+
+use anyhow::{bail, ensure};
 use derive_more::derive::From;
-use fly_into_the_maelstrom::*;
+use fly_into_the_maelstrom::{topology::Topology, *};
 use serde::{Deserialize, Serialize};
-use serde_with::{serde_as, OneOrMany};
+use serde_with::{serde_as, DisplayFromStr, OneOrMany};
 
 use outbox::Outbox;
 use retry_queue::RetryQueue;
@@ -44,7 +39,7 @@ struct ReadOkPayload {
 
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
 struct TopologyPayload {
-    topology: HashMap<NodeId, Vec<NodeId>>,
+    topology: Topology,
 }
 
 #[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize, From)]
@@ -58,43 +53,196 @@ enum Payload {
     TopologyOk,
 }
 
+/// Along which edges new values are forwarded.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+enum OverlayKind {
+    /// The node receiving a value from a client sends it to all other nodes
+    /// (`flood`, the default).
+    Flood,
+    /// The graph of Maelstrom's `topology` message (`maelstrom`).
+    Maelstrom,
+    /// A spanning tree of Maelstrom's graph, rooted at the cluster's leader
+    /// (`spanning-tree`).
+    SpanningTree,
+    /// A tree with `k` children per node (`tree:<k>`).
+    Tree(usize),
+    Grid,
+    Ring,
+    /// A random graph with `degree` neighbors per node (`random:<degree>`).
+    Random(usize),
+}
+
+impl OverlayKind {
+    /// Generates the overlay, unless it is flooding or depends on the
+    /// `topology` message.
+    fn generate(self, cluster: &Cluster) -> Option<Topology> {
+        let node_ids = cluster.node_ids();
+        match self {
+            OverlayKind::Flood | OverlayKind::Maelstrom | OverlayKind::SpanningTree => None,
+            OverlayKind::Tree(k) => Some(Topology::tree(node_ids, k)),
+            OverlayKind::Grid => Some(Topology::grid(node_ids)),
+            OverlayKind::Ring => Some(Topology::ring(node_ids)),
+            // All nodes need to use the same seed.
+            OverlayKind::Random(degree) => Some(Topology::random_regular(node_ids, degree, 0)),
+        }
+    }
+}
+
+impl fmt::Display for OverlayKind {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            OverlayKind::Flood => f.write_str("flood"),
+            OverlayKind::Maelstrom => f.write_str("maelstrom"),
+            OverlayKind::SpanningTree => f.write_str("spanning-tree"),
+            OverlayKind::Tree(k) => write!(f, "tree:{k}"),
+            OverlayKind::Grid => f.write_str("grid"),
+            OverlayKind::Ring => f.write_str("ring"),
+            OverlayKind::Random(degree) => write!(f, "random:{degree}"),
+        }
+    }
+}
+
+impl FromStr for OverlayKind {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let (name, arg) = match s.split_once(':') {
+            Some((name, arg)) => (name, Some(arg.parse::<usize>()?)),
+            None => (s, None),
+        };
+        Ok(match (name, arg) {
+            ("flood", None) => OverlayKind::Flood,
+            ("maelstrom", None) => OverlayKind::Maelstrom,
+            ("spanning-tree", None) => OverlayKind::SpanningTree,
+            ("tree", Some(k)) if k > 0 => OverlayKind::Tree(k),
+            ("grid", None) => OverlayKind::Grid,
+            ("ring", None) => OverlayKind::Ring,
+            ("random", Some(degree)) => OverlayKind::Random(degree),
+            _ => bail!("invalid topology: {s}"),
+        })
+    }
+}
+
+/// Set with `BROADCAST_<FIELD>` or `--<field>`, e.g. `BROADCAST_DELAY_MS=1000`.
+#[serde_as]
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+struct BroadcastConfig {
+    /// How long new values are collected before they are sent to a neighbor.
+    delay_ms: u64,
+    /// How long to wait for an acknowledgement before the first retry.
+    /// Later retries wait longer, up to `max_retry_backoff_ms`.
+    retry_backoff_ms: u64,
+    max_retry_backoff_ms: u64,
+    #[serde_as(as = "DisplayFromStr")]
+    topology: OverlayKind,
+}
+
+impl Default for BroadcastConfig {
+    fn default() -> Self {
+        Self {
+            delay_ms: 0,
+            retry_backoff_ms: 250,
+            max_retry_backoff_ms: 1250,
+            topology: OverlayKind::Flood,
+        }
+    }
+}
+
+impl Config for BroadcastConfig {
+    const ENV_PREFIX: &'static str = "BROADCAST_";
+
+    fn validate(&self) -> anyhow::Result<()> {
+        ensure!(
+            self.retry_backoff_ms > 0,
+            "retry_backoff_ms must be positive"
+        );
+        ensure!(
+            self.max_retry_backoff_ms >= self.retry_backoff_ms,
+            "max_retry_backoff_ms must not be below retry_backoff_ms"
+        );
+        Ok(())
+    }
+}
+
 #[derive(Debug)]
 struct BroadcastNode {
-    id: NodeId,
-    other_nodes: Box<[NodeId]>,
+    cluster: Cluster,
+    overlay_kind: OverlayKind,
+    /// `None` floods.
+    overlay: Option<Topology>,
     tx: MessageTransmitter<Payload>,
     values: BTreeSet<Value>,
     outbox: Outbox<BroadcastPayload>,
-    retry_queue: RetryQueue<BroadcastPayload>,
+    outbox_timer: Option<TimerId>,
+    retry_queue: RetryQueue<Payload>,
+    retry_timer: Option<TimerId>,
 }
 
 impl BroadcastNode {
-    fn new(
-        id: NodeId,
-        all_nodes: &[NodeId],
-        tx: MessageTransmitter<Payload>,
-        broadcast_delay: Duration,
-    ) -> Self {
-        let other_nodes = all_nodes.iter().filter(|&&n| n != id).copied().collect();
+    fn new(cluster: Cluster, tx: MessageTransmitter<Payload>, config: &BroadcastConfig) -> Self {
         Self {
-            id,
-            other_nodes,
+            overlay: config.topology.generate(&cluster),
+            overlay_kind: config.topology,
+            cluster,
             tx,
             values: BTreeSet::default(),
-            outbox: Outbox::new(broadcast_delay),
-            retry_queue: RetryQueue::new(Duration::from_millis(250)),
+            outbox: Outbox::new(Duration::from_millis(config.delay_ms)),
+            outbox_timer: None,
+            retry_queue: RetryQueue::new(
+                Duration::from_millis(config.retry_backoff_ms),
+                Duration::from_millis(config.max_retry_backoff_ms),
+            ),
+            retry_timer: None,
+        }
+    }
+
+    /// Makes sure a timer is pending for the outbox's next message.
+    fn schedule_outbox(&mut self) {
+        if self.outbox_timer.is_none() {
+            if let Some(send_after) = self.outbox.send_after() {
+                self.outbox_timer = Some(self.tx.timers().schedule_at(send_after));
+            }
+        }
+    }
+
+    /// Makes sure a timer is pending for the next retry, if any messages are
+    /// still unacknowledged.
+    fn schedule_retries(&mut self) {
+        if self.retry_timer.is_none() {
+            if let Some(send_after) = self.retry_queue.send_after() {
+                self.retry_timer = Some(self.tx.timers().schedule_at(send_after));
+            }
         }
     }
 
+    fn send_outbox(&mut self) {
+        for message in self.outbox.pop_messages_need_sending() {
+            let message = message.mapped();
+            self.tx.call_message(&message, None);
+            self.retry_queue.insert(message);
+        }
+        self.schedule_retries();
+    }
+
+    fn send_retries(&mut self) {
+        self.retry_queue
+            .retry_messages(|message| self.tx.send_message(message));
+        self.schedule_retries();
+    }
+
     fn broadcast_destinations(&self, src: NodeId) -> Box<[NodeId]> {
-        if self.id == src || self.other_nodes.contains(&src) {
-            Box::new([])
-        } else {
-            self.other_nodes.clone()
+        match &self.overlay {
+            None if self.cluster.node_ids().contains(&src) => Box::new([]),
+            None => self.cluster.peers().into(),
+            Some(overlay) => overlay
+                .neighbors(self.cluster.node_id())
+                .filter(|&neighbor| neighbor != src)
+                .collect(),
         }
     }
 
-    fn handle_broadcast(&mut self, Message { header, payload }: Message<BroadcastPayload>) {
+    fn handle_broadcast(&mut self, header: RequestHeader, payload: BroadcastPayload) {
         let values: BTreeSet<Value> = payload.values.into_iter().collect();
         let new_values: BTreeSet<Value> = values.difference(&self.values).copied().collect();
         self.values.extend(&new_values);
@@ -110,12 +258,13 @@ impl BroadcastNode {
                     },
                 ));
             }
+            self.schedule_outbox();
         }
 
         self.tx.reply(&header, Payload::BroadcastOk);
     }
 
-    fn handle_read(&mut self, header: &MessageHeader) {
+    fn handle_read(&mut self, header: &RequestHeader) {
         self.tx.reply(
             header,
             Payload::ReadOk(ReadOkPayload {
@@ -124,54 +273,62 @@ impl BroadcastNode {
         );
     }
 
-    fn handle_topology(&mut self, header: &MessageHeader) {
+    fn handle_topology(&mut self, header: &RequestHeader, payload: TopologyPayload) {
+        match self.overlay_kind {
+            OverlayKind::Maelstrom => self.overlay = Some(payload.topology),
+            OverlayKind::SpanningTree => {
+                let tree = payload.topology.spanning_tree(self.cluster.leader());
+                self.overlay = Some(tree);
+            }
+            _ => (),
+        }
         self.tx.reply(header, Payload::TopologyOk);
     }
+}
 
-    fn handle_broadcast_ok(&mut self, header: &MessageHeader) {
-        assert!(header.in_reply_to.is_some());
-        self.retry_queue
-            .remove(|message| message.header.msg_id == header.in_reply_to);
+impl TypedNodeState for BroadcastNode {
+    type Request = Payload;
+    type Response = Payload;
+
+    fn transmitter(&mut self) -> &mut MessageTransmitter<Payload> {
+        &mut self.tx
     }
-}
 
-impl NodeState for BroadcastNode {
-    fn handle(mut self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>> {
+    fn handle_request(&mut self, request: Message<Payload>) -> Transition {
         use Payload::*;
-        let Message { header, payload } = deserialize_message(request)?;
+        let Message { header, payload } = request;
         match payload {
-            Broadcast(payload) => self.handle_broadcast(Message { header, payload }),
-            Read => self.handle_read(&header),
-            Topology(_) => self.handle_topology(&header),
-            BroadcastOk => self.handle_broadcast_ok(&header),
-            _ => (),
+            Broadcast(payload) => self.handle_broadcast(header.request()?, payload),
+            Read => self.handle_read(&header.request()?),
+            Topology(payload) => self.handle_topology(&header.request()?, payload),
+            // Duplicate acknowledgements for retried broadcasts. Nodes never
+            // send the other responses to each other.
+            BroadcastOk | ReadOk(_) | TopologyOk => (),
         }
-        Ok(self)
+        Ok(None)
     }
 
-    fn next_wake_up(&self) -> Option<Instant> {
-        match (self.outbox.send_after(), self.retry_queue.send_after()) {
-            (None, None) => None,
-            (None, Some(a)) | (Some(a), None) => Some(a),
-            (Some(a), Some(b)) => Some(a.min(b)),
+    fn wake_up(&mut self, timer: TimerId) -> Transition {
+        if Some(timer) == self.retry_timer {
+            self.retry_timer = None;
+            self.send_retries();
+        } else if Some(timer) == self.outbox_timer {
+            self.outbox_timer = None;
+            self.send_outbox();
+            self.schedule_outbox();
         }
+        Ok(None)
     }
 
-    fn wake_up(mut self: Box<Self>) -> Result<Box<dyn NodeState>> {
-        for message in self.outbox.pop_messages_need_sending() {
-            self.tx.send_message(&message.clone().mapped());
-            self.retry_queue.insert(message);
-        }
-        for message in self
-            .retry_queue
-            .retry_messages()
-            .into_iter()
-            .map(Message::mapped)
-        {
-            self.tx.send_message(&message);
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        self.retry_queue
+            .remove(|message| message.header.msg_id == Some(response.request_id));
+        if self.retry_queue.is_empty() {
+            if let Some(timer) = self.retry_timer.take() {
+                self.tx.timers().cancel(timer);
+            }
         }
-
-        Ok(self)
+        Ok(None)
     }
 }
 
@@ -182,7 +339,7 @@ mod outbox {
         time::{Duration, Instant},
     };
 
-    use super::Message;
+    use super::{now, Message};
 
     #[derive(Default, Debug)]
     pub struct Outbox<P> {
@@ -207,7 +364,7 @@ mod outbox {
             } else {
                 self.inner.push_back(OutboxEntry {
                     message,
-                    send_after: Instant::now() + self.delay,
+                    send_after: now() + self.delay,
                 });
             }
         }
@@ -229,7 +386,7 @@ mod outbox {
             if let Some(last_idx) = self
                 .inner
                 .iter()
-                .rposition(|entry| entry.send_after <= Instant::now())
+                .rposition(|entry| entry.send_after <= now())
             {
                 self.inner
                     .drain(..=last_idx)
@@ -248,12 +405,13 @@ mod retry_queue {
         time::{Duration, Instant},
     };
 
-    use super::Message;
+    use super::{now, Message};
 
     #[derive(Default, Debug)]
     pub struct RetryQueue<P> {
         inner: VecDeque<RetryEntry<P>>,
         backoff: Duration,
+        max_backoff: Duration,
     }
 
     #[derive(Debug)]
@@ -263,20 +421,17 @@ mod retry_queue {
         count: u8,
     }
 
-    impl<P: Clone> RetryQueue<P> {
-        pub fn new(backoff: Duration) -> Self {
+    impl<P> RetryQueue<P> {
+        pub fn new(backoff: Duration, max_backoff: Duration) -> Self {
             Self {
                 inner: VecDeque::default(),
                 backoff,
+                max_backoff,
             }
         }
 
         fn backoff(&self, retry_count: u8) -> Instant {
-            Instant::now() + self.backoff * u8::min(retry_count + 1, 5) as u32
-        }
-
-        pub fn send_after(&self) -> Option<Instant> {
-            self.inner.front().map(|entry| entry.send_after)
+            now() + Duration::min(self.backoff * (retry_count as u32 + 1), self.max_backoff)
         }
 
         fn insert_entry(&mut self, entry: RetryEntry<P>) {
@@ -296,6 +451,14 @@ mod retry_queue {
             });
         }
 
+        pub fn is_empty(&self) -> bool {
+            self.inner.is_empty()
+        }
+
+        pub fn send_after(&self) -> Option<Instant> {
+            self.inner.front().map(|entry| entry.send_after)
+        }
+
         pub fn remove(&mut self, mut predicate: impl FnMut(&Message<P>) -> bool) {
             if let Some(idx) = self
                 .inner
@@ -306,40 +469,130 @@ mod retry_queue {
             }
         }
 
-        pub fn retry_messages(&mut self) -> Vec<Message<P>> {
+        /// Passes every message that is due to `send` and schedules its next
+        /// retry.
+        pub fn retry_messages(&mut self, mut send: impl FnMut(&Message<P>)) {
             if let Some(last_idx) = self
                 .inner
                 .iter()
-                .rposition(|entry| entry.send_after <= Instant::now())
+                .rposition(|entry| entry.send_after <= now())
             {
                 let entries: Vec<_> = self.inner.drain(..=last_idx).collect();
-                let mut messages = Vec::new();
                 for mut entry in entries {
-                    messages.push(entry.message.clone());
+                    send(&entry.message);
                     entry.count += 1;
                     entry.send_after = self.backoff(entry.count);
                     self.insert_entry(entry);
                 }
-                messages
-            } else {
-                vec![]
             }
         }
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    let broadcast_delay = Duration::from_millis(
-        env::var("BROADCAST_DELAY_MS")
-            .unwrap_or("0".to_owned())
-            .parse()?,
-    );
-    run_node(Box::new(move |init, tx| {
-        Box::new(BroadcastNode::new(
-            init.node_id,
-            &init.node_ids,
+    run_configured_node(Box::new(|config: &BroadcastConfig, init, tx| {
+        Ok(Box::new(BroadcastNode::new(
+            init.cluster(),
             tx.into(),
-            broadcast_delay,
-        ))
+            config,
+        )))
     }))
 }
+
+#[cfg(test)]
+mod tests {
+    use fly_into_the_maelstrom::sim::*;
+
+    use super::*;
+
+    fn assert_all_nodes_receive_all_values(overlay_kind: OverlayKind) {
+        let options = SimulationOptions {
+            seed: 7,
+            latency: Latency::Uniform {
+                min: Duration::ZERO,
+                max: Duration::from_millis(100),
+            },
+            ..Default::default()
+        };
+        let mut sim = Simulation::new(
+            5,
+            options,
+            Box::new(move |init, tx| {
+                let config = BroadcastConfig {
+                    delay_ms: 50,
+                    topology: overlay_kind,
+                    ..Default::default()
+                };
+                Ok(Box::new(BroadcastNode::new(
+                    init.cluster(),
+                    tx.into(),
+                    &config,
+                )))
+            }),
+        )
+        .unwrap();
+        let client: NodeId = "c1".parse().unwrap();
+        let nodes = sim.node_ids();
+        for (value, dest) in (0..20).zip(nodes.iter().cycle()) {
+            let payload = BroadcastPayload {
+                values: vec![value],
+            };
+            sim.send(client, *dest, Payload::Broadcast(payload));
+        }
+        sim.run_for(Duration::from_secs(2)).unwrap();
+        for dest in &nodes {
+            sim.send(client, *dest, Payload::Read);
+        }
+        sim.run_for(Duration::from_secs(1)).unwrap();
+
+        let reads: Vec<_> = sim
+            .take_client_messages::<Payload>(client)
+            .unwrap()
+            .into_iter()
+            .filter_map(|message| match message.payload {
+                Payload::ReadOk(payload) => Some(payload.values),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(reads.len(), nodes.len());
+        for values in reads {
+            assert_eq!(values.as_ref(), (0..20).collect::<Vec<_>>());
+        }
+    }
+
+    #[test]
+    fn all_nodes_receive_all_values() {
+        assert_all_nodes_receive_all_values(OverlayKind::Flood);
+    }
+
+    #[test]
+    fn values_are_forwarded_along_overlay() {
+        assert_all_nodes_receive_all_values(OverlayKind::Tree(2));
+        assert_all_nodes_receive_all_values(OverlayKind::Ring);
+    }
+
+    #[test]
+    fn parses_overlay_kinds() {
+        assert_eq!(
+            "tree:4".parse::<OverlayKind>().unwrap(),
+            OverlayKind::Tree(4)
+        );
+        assert_eq!("grid".parse::<OverlayKind>().unwrap(), OverlayKind::Grid);
+        assert!("tree".parse::<OverlayKind>().is_err());
+        assert!("tree:0".parse::<OverlayKind>().is_err());
+        assert!("ring:2".parse::<OverlayKind>().is_err());
+    }
+
+    #[test]
+    fn loads_config() {
+        let vars = [("BROADCAST_DELAY_MS".to_owned(), "1000".to_owned())];
+        let args = ["--topology=tree:4".to_owned()];
+        let config = BroadcastConfig::from_sources(vars, args).unwrap();
+        assert_eq!(config.delay_ms, 1000);
+        assert_eq!(config.topology, OverlayKind::Tree(4));
+        assert_eq!(config.retry_backoff_ms, 250);
+
+        let args = ["--max-retry-backoff-ms".to_owned(), "100".to_owned()];
+        assert!(BroadcastConfig::from_sources([], args).is_err());
+    }
+}
@@ -1,4 +1,3 @@
-use anyhow::Result;
 use fly_into_the_maelstrom::*;
 use serde::{Deserialize, Serialize};
 
@@ -20,20 +19,17 @@ struct EchoNode {
 }
 
 impl NodeState for EchoNode {
-    fn handle(mut self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>> {
+    fn handle(&mut self, request: &str) -> Transition {
         let Message {
             header,
             payload: RequestPayload::Echo { echo },
         } = deserialize_message(request)?;
-        self.tx.reply(&header, ResponsePayload::EchoOk { echo });
-        Ok(self)
-    }
-
-    fn wake_up(self: Box<Self>) -> Result<Box<dyn NodeState>> {
-        Ok(self)
+        self.tx
+            .reply(&header.request()?, ResponsePayload::EchoOk { echo });
+        Ok(None)
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    run_node(Box::new(|_, tx| Box::new(EchoNode { tx: tx.into() })))
+    run_node(Box::new(|_, tx| Ok(Box::new(EchoNode { tx: tx.into() }))))
 }
@@ -1,6 +1,5 @@
 use std::{collections::HashMap, ops::RangeFrom};
 
-use anyhow::Result;
 use fly_into_the_maelstrom::*;
 use serde::{Deserialize, Serialize};
 
@@ -100,13 +99,13 @@ impl KafkaNode {
         Some((key, offset))
     }
 
-    fn handle_send(&mut self, header: MessageHeader, key: LogKey, value: Value) {
+    fn handle_send(&mut self, header: RequestHeader, key: LogKey, value: Value) {
         let log = self.logs.entry(key).or_default();
         let offset = log.append(value);
         self.tx.reply(&header, ResponsePayload::SendOk { offset });
     }
 
-    fn handle_poll(&mut self, header: MessageHeader, offsets: HashMap<String, u64>) {
+    fn handle_poll(&mut self, header: RequestHeader, offsets: HashMap<String, u64>) {
         self.tx.reply(
             &header,
             ResponsePayload::PollOk {
@@ -123,7 +122,7 @@ impl KafkaNode {
         );
     }
 
-    fn handle_commit_offsets(&mut self, header: MessageHeader, offsets: HashMap<String, u64>) {
+    fn handle_commit_offsets(&mut self, header: RequestHeader, offsets: HashMap<String, u64>) {
         for (key, offset) in offsets.into_iter() {
             if let Some(log) = self.logs.get_mut(&key) {
                 log.commit(header.src, offset);
@@ -132,7 +131,7 @@ impl KafkaNode {
         self.tx.reply(&header, ResponsePayload::CommitOffsetsOk);
     }
 
-    fn handle_list_committed_offsets(&mut self, header: MessageHeader, keys: Vec<String>) {
+    fn handle_list_committed_offsets(&mut self, header: RequestHeader, keys: Vec<String>) {
         self.tx.reply(
             &header,
             ResponsePayload::ListCommittedOffsetsOk {
@@ -145,9 +144,17 @@ impl KafkaNode {
     }
 }
 
-impl NodeState for KafkaNode {
-    fn handle(mut self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>> {
-        let Message::<RequestPayload> { header, payload } = deserialize_message(request)?;
+impl TypedNodeState for KafkaNode {
+    type Request = RequestPayload;
+    type Response = ResponsePayload;
+
+    fn transmitter(&mut self) -> &mut MessageTransmitter<ResponsePayload> {
+        &mut self.tx
+    }
+
+    fn handle_request(&mut self, request: Message<RequestPayload>) -> Transition {
+        let Message { header, payload } = request;
+        let header = header.request()?;
         use RequestPayload::*;
         match payload {
             Send { key, value } => self.handle_send(header, key, value),
@@ -155,14 +162,10 @@ impl NodeState for KafkaNode {
             CommitOffsets { offsets } => self.handle_commit_offsets(header, offsets),
             ListCommittedOffsets { keys } => self.handle_list_committed_offsets(header, keys),
         };
-        Ok(self)
-    }
-
-    fn wake_up(self: Box<Self>) -> Result<Box<dyn NodeState>> {
-        Ok(self)
+        Ok(None)
     }
 }
 
 fn main() -> anyhow::Result<()> {
-    run_node(Box::new(|_, tx| Box::new(KafkaNode::new(tx.into()))))
+    run_node(Box::new(|_, tx| Ok(Box::new(KafkaNode::new(tx.into())))))
 }
@@ -0,0 +1,360 @@
+//! Runs a cluster of nodes locally, without Maelstrom.
+//!
+//! ```text
+//! maelstrom-router --bin <path> [--node-count <n>] [--listen <address>]
+//! ```
+//!
+//! Spawns `n` copies of the node binary (`n0`, `n1`, ...), initializes them
+//! and routes every line a node writes to STDOUT to the STDIN of its `dest`.
+//! In-process versions of `seq-kv`, `lin-kv` and `lww-kv` are available, too.
+//!
+//! Clients connect via TCP (default: `127.0.0.1:4000`) and write one message
+//! per line. Each connection gets its own client id (`c1`, `c2`, ...), which
+//! replaces the messages' `src`, so replies come back on the same connection.
+//! The router stops once all nodes exited or on SIGTERM/SIGINT.
+
+use std::{
+    collections::HashMap,
+    io::{BufRead, BufReader, Write},
+    net::{TcpListener, TcpStream},
+    process::{Child, Command, Stdio},
+    sync::{mpsc, Arc},
+    thread,
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use fly_into_the_maelstrom::{
+    services::{KvModel, KvService},
+    transport::{MemoryTransport, Transport},
+    *,
+};
+use serde::Serialize;
+use serde_json::Value;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+/// The client sending `init` messages, i.e. the router itself.
+const ROUTER: NodeId = node_id!("c0");
+
+#[derive(Debug)]
+struct Args {
+    bin: String,
+    node_count: usize,
+    listen: String,
+}
+
+impl Args {
+    fn parse() -> Result<Self> {
+        let mut bin = None;
+        let mut node_count = 1;
+        let mut listen = "127.0.0.1:4000".to_owned();
+        let mut args = std::env::args().skip(1);
+        while let Some(arg) = args.next() {
+            let mut value = || {
+                args.next()
+                    .ok_or_else(|| anyhow!("missing value for {arg}"))
+            };
+            match arg.as_str() {
+                "--bin" => bin = Some(value()?),
+                "--node-count" => node_count = value()?.parse().context("invalid --node-count")?,
+                "--listen" => listen = value()?,
+                _ => bail!(
+                    "unexpected argument {arg:?}\n\
+                     usage: maelstrom-router --bin <path> [--node-count <n>] [--listen <address>]"
+                ),
+            }
+        }
+        if node_count == 0 {
+            bail!("--node-count must be positive");
+        }
+        Ok(Self {
+            bin: bin.ok_or_else(|| anyhow!("missing --bin"))?,
+            node_count,
+            listen,
+        })
+    }
+}
+
+/// What the router thread reacts to.
+enum Event {
+    /// A line some node, service or client sent.
+    Message(String),
+    /// A client connected, messages to `NodeId` go to the writer.
+    Connected(NodeId, Box<dyn Write + Send>),
+    /// A client disconnected or a node closed its STDOUT.
+    Disconnected(NodeId),
+    /// SIGTERM or SIGINT.
+    Shutdown,
+}
+
+#[derive(Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RouterPayload {
+    Init {
+        node_id: NodeId,
+        node_ids: Box<[NodeId]>,
+    },
+}
+
+struct Router {
+    /// Where messages to each node, service and client are written.
+    endpoints: HashMap<NodeId, Box<dyn Write + Send>>,
+    msg_ids: std::ops::RangeFrom<u64>,
+    logger: Arc<Logger>,
+}
+
+impl Router {
+    fn log(&self, level: Level, text: &str) {
+        self.logger.log(level, module_path!(), text);
+    }
+
+    /// Sends an `init` message to `node_id`.
+    fn init(&mut self, node_id: NodeId, node_ids: &[NodeId]) {
+        let message = Message {
+            header: MessageHeader {
+                src: ROUTER,
+                dest: node_id,
+                msg_id: self.msg_ids.next().map(MessageId::from),
+                in_reply_to: None,
+            },
+            payload: RouterPayload::Init {
+                node_id,
+                node_ids: node_ids.into(),
+            },
+        };
+        self.route(serialize_message(&message));
+    }
+
+    fn route(&mut self, mut line: String) {
+        let dest = match deserialize_header(&line) {
+            Ok(header) => header.dest,
+            Err(err) => {
+                self.log(
+                    Level::Warn,
+                    &format!("dropping invalid message {line}: {err}"),
+                );
+                return;
+            }
+        };
+        if dest == ROUTER {
+            self.log(Level::Info, &format!("< {line}"));
+            return;
+        }
+        let Some(endpoint) = self.endpoints.get_mut(&dest) else {
+            self.log(
+                Level::Warn,
+                &format!("dropping message to unknown {dest}: {line}"),
+            );
+            return;
+        };
+        line.push('\n');
+        if let Err(err) = endpoint
+            .write_all(line.as_bytes())
+            .and_then(|()| endpoint.flush())
+        {
+            self.log(Level::Warn, &format!("{dest} is gone: {err}"));
+            self.endpoints.remove(&dest);
+        }
+    }
+}
+
+/// Sends every line of `reader` to the router, then [Event::Disconnected].
+fn forward_lines(
+    node_id: NodeId,
+    reader: impl BufRead,
+    events: mpsc::Sender<Event>,
+    map: impl Fn(String) -> Option<String>,
+) {
+    for line in reader.lines().map_while(Result::ok) {
+        if let Some(line) = map(line) {
+            if events.send(Event::Message(line)).is_err() {
+                return;
+            }
+        }
+    }
+    let _ = events.send(Event::Disconnected(node_id));
+}
+
+fn spawn_node(bin: &str, node_id: NodeId, events: &mpsc::Sender<Event>) -> Result<Child> {
+    let mut child = Command::new(bin)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("failed to spawn {bin}"))?;
+    let stdout = BufReader::new(child.stdout.take().expect("stdout should be piped"));
+    let events = events.clone();
+    thread::spawn(move || forward_lines(node_id, stdout, events, Some));
+    Ok(child)
+}
+
+/// Runs a [KvService] in-process and returns its end of the connection.
+fn spawn_service(model: KvModel, events: &mpsc::Sender<Event>) -> Result<Box<dyn Write + Send>> {
+    let (service_end, router_end) = MemoryTransport::pair();
+    let seed = std::process::id().into();
+    thread::spawn(move || {
+        run_node_on(
+            service_end,
+            NodeOptions::default(),
+            Box::new(move |_, tx| Ok(Box::new(KvService::new(model, tx, seed)))),
+        )
+    });
+    let (reader, writer) = router_end.split()?;
+    let events = events.clone();
+    thread::spawn(move || forward_lines(model.node_id(), reader, events, Some));
+    Ok(Box::new(writer))
+}
+
+/// Accepts clients and forwards their messages with `src` set to their id.
+fn accept_clients(listener: TcpListener, events: mpsc::Sender<Event>, logger: Arc<Logger>) {
+    for (i, stream) in listener.incoming().enumerate() {
+        let client_id: NodeId = match format!("c{}", i + 1).parse() {
+            Ok(client_id) => client_id,
+            Err(err) => {
+                logger.log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("no more clients: {err}"),
+                );
+                return;
+            }
+        };
+        let streams = stream.and_then(|stream| Ok((stream.try_clone()?, stream)));
+        let (reader, writer): (TcpStream, TcpStream) = match streams {
+            Ok(streams) => streams,
+            Err(err) => {
+                logger.log(
+                    Level::Warn,
+                    module_path!(),
+                    &format!("accept failed: {err}"),
+                );
+                continue;
+            }
+        };
+        if events
+            .send(Event::Connected(client_id, Box::new(writer)))
+            .is_err()
+        {
+            return;
+        }
+        logger.log(
+            Level::Info,
+            module_path!(),
+            &format!("{client_id} connected"),
+        );
+        let events = events.clone();
+        let logger = Arc::clone(&logger);
+        thread::spawn(move || {
+            forward_lines(client_id, BufReader::new(reader), events, |line| {
+                match serde_json::from_str::<Value>(&line) {
+                    Ok(mut message) if message.is_object() => {
+                        message["src"] = Value::String(client_id.to_string());
+                        Some(message.to_string())
+                    }
+                    _ => {
+                        let text = format!("{client_id} sent an invalid message: {line}");
+                        logger.log(Level::Warn, module_path!(), &text);
+                        None
+                    }
+                }
+            })
+        });
+    }
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse()?;
+    let logger = Arc::new(Logger::from_env());
+    let (events_tx, events) = mpsc::channel();
+
+    let node_ids: Box<[NodeId]> = (0..args.node_count)
+        .map(|i| format!("n{i}").parse())
+        .collect::<Result<_, _>>()
+        .map_err(|err| anyhow!("invalid node count: {err}"))?;
+    let mut router = Router {
+        endpoints: HashMap::new(),
+        msg_ids: 1..,
+        logger: Arc::clone(&logger),
+    };
+    let mut children = Vec::new();
+    for &node_id in node_ids.iter() {
+        let mut child = spawn_node(&args.bin, node_id, &events_tx)?;
+        let stdin = child.stdin.take().expect("stdin should be piped");
+        router.endpoints.insert(node_id, Box::new(stdin));
+        children.push(child);
+    }
+    for model in [
+        KvModel::Sequential,
+        KvModel::Linearizable,
+        KvModel::LastWriteWins,
+    ] {
+        let service = spawn_service(model, &events_tx)?;
+        router.endpoints.insert(model.node_id(), service);
+        router.init(model.node_id(), &[model.node_id()]);
+    }
+    for &node_id in node_ids.iter() {
+        router.init(node_id, &node_ids);
+    }
+
+    let listener = TcpListener::bind(&args.listen)
+        .with_context(|| format!("failed to listen on {}", args.listen))?;
+    let address = listener.local_addr()?;
+    logger.log(
+        Level::Info,
+        module_path!(),
+        &format!("listening on {address}"),
+    );
+    thread::spawn({
+        let events = events_tx.clone();
+        let logger = Arc::clone(&logger);
+        move || accept_clients(listener, events, logger)
+    });
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    thread::spawn({
+        let events = events_tx.clone();
+        move || {
+            if signals.forever().next().is_some() {
+                let _ = events.send(Event::Shutdown);
+            }
+        }
+    });
+    drop(events_tx);
+
+    let mut running = node_ids.len();
+    for event in events {
+        match event {
+            Event::Message(line) => router.route(line),
+            Event::Connected(client_id, writer) => {
+                router.endpoints.insert(client_id, writer);
+            }
+            Event::Disconnected(node_id) => {
+                router.endpoints.remove(&node_id);
+                if node_ids.contains(&node_id) {
+                    running -= 1;
+                    if running == 0 {
+                        break;
+                    }
+                }
+            }
+            // Closing their STDIN shuts the nodes down.
+            Event::Shutdown => {
+                for node_id in node_ids.iter() {
+                    router.endpoints.remove(node_id);
+                }
+            }
+        }
+    }
+
+    for mut child in children {
+        let status = child.wait()?;
+        if !status.success() {
+            logger.log(
+                Level::Warn,
+                module_path!(),
+                &format!("node exited: {status}"),
+            );
+        }
+    }
+    Ok(())
+}
@@ -36,25 +36,29 @@ impl UniqueIdsNode {
     }
 }
 
-impl NodeState for UniqueIdsNode {
-    fn handle(mut self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>> {
-        let Message::<RequestPayload> { header, .. } = deserialize_message(request)?;
-        let id = self.next_unique_id()?;
-        self.tx.reply(&header, ResponsePayload::GenerateOk { id });
-        Ok(self)
+impl TypedNodeState for UniqueIdsNode {
+    type Request = RequestPayload;
+    type Response = ResponsePayload;
+
+    fn transmitter(&mut self) -> &mut MessageTransmitter<ResponsePayload> {
+        &mut self.tx
     }
 
-    fn wake_up(self: Box<Self>) -> Result<Box<dyn NodeState>> {
-        Ok(self)
+    fn handle_request(&mut self, request: Message<RequestPayload>) -> Transition {
+        let Message { header, .. } = request;
+        let id = self.next_unique_id()?;
+        self.tx
+            .reply(&header.request()?, ResponsePayload::GenerateOk { id });
+        Ok(None)
     }
 }
 
 fn main() -> anyhow::Result<()> {
     run_node(Box::new(|init, tx| {
-        Box::new(UniqueIdsNode {
+        Ok(Box::new(UniqueIdsNode {
             id: init.node_id,
             tx: tx.into(),
             internal_ids: 0..,
-        })
+        }))
     }))
 }
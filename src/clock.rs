@@ -0,0 +1,29 @@
+use std::{cell::Cell, time::Instant};
+
+thread_local! {
+    static VIRTUAL_NOW: Cell<Option<Instant>> = const { Cell::new(None) };
+}
+
+/// Returns the current time.
+///
+/// Node code should use this instead of [Instant::now]: when running inside a
+/// [crate::sim::Simulation], it returns the simulation's virtual time.
+pub fn now() -> Instant {
+    VIRTUAL_NOW.get().unwrap_or_else(Instant::now)
+}
+
+/// Overrides [now] on the current thread until the guard is dropped.
+pub(crate) fn set_virtual_now(instant: Instant) -> VirtualNowGuard {
+    let previous = VIRTUAL_NOW.replace(Some(instant));
+    VirtualNowGuard { previous }
+}
+
+pub(crate) struct VirtualNowGuard {
+    previous: Option<Instant>,
+}
+
+impl Drop for VirtualNowGuard {
+    fn drop(&mut self) {
+        VIRTUAL_NOW.set(self.previous);
+    }
+}
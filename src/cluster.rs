@@ -0,0 +1,202 @@
+use std::hash::{Hash, Hasher};
+
+use crate::{rng::Rng, InitPayload, NodeId};
+
+/// The nodes of a cluster as seen by one of them.
+///
+/// Create it with [InitPayload::cluster]. Everything here only depends on the
+/// `init` message, so all nodes agree on e.g. the [Cluster::leader] and the
+/// [Cluster::owner] of a key without talking to each other.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct Cluster {
+    node_id: NodeId,
+    /// In the order Maelstrom sent them.
+    node_ids: Box<[NodeId]>,
+    index: usize,
+    peers: Box<[NodeId]>,
+}
+
+impl Cluster {
+    /// Creates the cluster of `node_ids` as seen by `node_id`.
+    ///
+    /// `node_id` is added to `node_ids` if it is missing.
+    pub fn new(node_id: NodeId, node_ids: &[NodeId]) -> Self {
+        let mut node_ids = node_ids.to_vec();
+        let index = match node_ids.iter().position(|&n| n == node_id) {
+            Some(index) => index,
+            None => {
+                node_ids.push(node_id);
+                node_ids.len() - 1
+            }
+        };
+        let peers = node_ids.iter().copied().filter(|&n| n != node_id).collect();
+        Self {
+            node_id,
+            node_ids: node_ids.into(),
+            index,
+            peers,
+        }
+    }
+
+    /// The local node's id.
+    pub fn node_id(&self) -> NodeId {
+        self.node_id
+    }
+
+    /// All nodes, including the local one.
+    pub fn node_ids(&self) -> &[NodeId] {
+        &self.node_ids
+    }
+
+    /// All nodes except the local one.
+    pub fn peers(&self) -> &[NodeId] {
+        &self.peers
+    }
+
+    /// The local node's position in [Cluster::node_ids].
+    pub fn index(&self) -> usize {
+        self.index
+    }
+
+    /// The number of nodes.
+    pub fn size(&self) -> usize {
+        self.node_ids.len()
+    }
+
+    /// The smallest number of nodes such that any two such groups overlap.
+    pub fn majority(&self) -> usize {
+        self.size() / 2 + 1
+    }
+
+    /// How many nodes may fail while a [Cluster::majority] is still reachable.
+    pub fn max_failures(&self) -> usize {
+        self.size() - self.majority()
+    }
+
+    /// A node every node agrees on, e.g. to coordinate something.
+    ///
+    /// This is the smallest id, it doesn't change unless the membership does.
+    pub fn leader(&self) -> NodeId {
+        *self
+            .node_ids
+            .iter()
+            .min()
+            .expect("a cluster contains at least the local node")
+    }
+
+    pub fn is_leader(&self) -> bool {
+        self.leader() == self.node_id
+    }
+
+    /// The node responsible for `key`.
+    ///
+    /// This uses rendezvous hashing, so if a node leaves the cluster, only its
+    /// keys move to other nodes.
+    pub fn owner(&self, key: &(impl Hash + ?Sized)) -> NodeId {
+        self.owners(key, 1)[0]
+    }
+
+    /// The `n` nodes responsible for `key` (e.g. its replicas), the
+    /// [Cluster::owner] first.
+    ///
+    /// Returns all nodes if there are fewer than `n`.
+    pub fn owners(&self, key: &(impl Hash + ?Sized), n: usize) -> Vec<NodeId> {
+        let key = stable_hash(key);
+        let mut scored: Vec<_> = self
+            .node_ids
+            .iter()
+            .map(|&node_id| {
+                let score = Rng::new(key ^ stable_hash(&node_id).rotate_left(32)).next_u64();
+                (std::cmp::Reverse(score), node_id)
+            })
+            .collect();
+        scored.sort_unstable();
+        scored
+            .into_iter()
+            .take(n)
+            .map(|(_, node_id)| node_id)
+            .collect()
+    }
+
+    /// Whether the local node is the [Cluster::owner] of `key`.
+    pub fn is_owner(&self, key: &(impl Hash + ?Sized)) -> bool {
+        self.owner(key) == self.node_id
+    }
+}
+
+impl InitPayload {
+    /// The cluster described by the `init` message.
+    pub fn cluster(&self) -> Cluster {
+        Cluster::new(self.node_id, &self.node_ids)
+    }
+}
+
+/// Hashes `value` the same way in every process (FNV-1a), unlike
+/// [std::collections::hash_map::DefaultHasher], which may change between Rust
+/// versions.
+fn stable_hash(value: &(impl Hash + ?Sized)) -> u64 {
+    struct Fnv(u64);
+
+    impl Hasher for Fnv {
+        fn finish(&self) -> u64 {
+            self.0
+        }
+
+        fn write(&mut self, bytes: &[u8]) {
+            for &b in bytes {
+                self.0 = (self.0 ^ u64::from(b)).wrapping_mul(0x100_0000_01b3);
+            }
+        }
+    }
+
+    let mut hasher = Fnv(0xcbf2_9ce4_8422_2325);
+    value.hash(&mut hasher);
+    hasher.finish()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn cluster(node_id: &str, size: usize) -> Cluster {
+        let node_ids: Vec<NodeId> = (0..size)
+            .map(|i| format!("n{i}").parse().unwrap())
+            .collect();
+        Cluster::new(node_id.parse().unwrap(), &node_ids)
+    }
+
+    #[test]
+    fn sizes_and_peers() {
+        let cluster = cluster("n2", 5);
+        assert_eq!(cluster.index(), 2);
+        assert_eq!(cluster.peers().len(), 4);
+        assert!(!cluster.peers().contains(&cluster.node_id()));
+        assert_eq!(cluster.majority(), 3);
+        assert_eq!(cluster.max_failures(), 2);
+        assert_eq!(cluster.leader().to_string(), "n0");
+        assert!(!cluster.is_leader());
+    }
+
+    #[test]
+    fn nodes_agree_on_owners() {
+        let a = cluster("n0", 5);
+        let b = cluster("n3", 5);
+        for key in 0..100 {
+            assert_eq!(a.owner(&key), b.owner(&key));
+        }
+        let owners: std::collections::BTreeSet<_> = (0..100).map(|key| a.owner(&key)).collect();
+        assert_eq!(owners.len(), 5, "keys should be spread over all nodes");
+    }
+
+    #[test]
+    fn removing_a_node_only_moves_its_keys() {
+        let full = cluster("n0", 5);
+        let reduced = Cluster::new(full.node_id(), &full.node_ids()[..4]);
+        for key in ["a", "b", "c", "d", "e", "f", "g", "h"] {
+            if full.owner(key) != full.node_ids()[4] {
+                assert_eq!(full.owner(key), reduced.owner(key));
+            }
+        }
+        assert_eq!(full.owners("a", 10).len(), 5);
+    }
+}
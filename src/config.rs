@@ -0,0 +1,271 @@
+//! Settings of a node from environment variables and command-line arguments,
+//! see [Config].
+
+use std::{collections::BTreeMap, fmt, str::FromStr};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{
+    de::{self, value::MapDeserializer, DeserializeOwned, IntoDeserializer, Visitor},
+    forward_to_deserialize_any, Deserializer, Serialize,
+};
+
+/// A node's settings, loaded by [crate::run_configured_node].
+///
+/// Every field can be set with an environment variable (the field's name in
+/// upper case, prefixed by [Config::ENV_PREFIX]) or a command-line argument
+/// (`--field-name value` or `--field-name=value`), arguments win. Missing
+/// fields take their value from [Default] (use `#[serde(default)]`), and
+/// `#[serde(deny_unknown_fields)]` turns typos into errors:
+///
+/// ```
+/// # use fly_into_the_maelstrom::Config;
+/// # use serde::{Deserialize, Serialize};
+/// #[derive(Debug, Default, Serialize, Deserialize)]
+/// #[serde(default, deny_unknown_fields)]
+/// struct GossipConfig {
+///     delay_ms: u64,
+///     fanout: usize,
+/// }
+///
+/// impl Config for GossipConfig {
+///     const ENV_PREFIX: &'static str = "GOSSIP_";
+///
+///     fn validate(&self) -> anyhow::Result<()> {
+///         anyhow::ensure!(self.fanout > 0, "fanout must be positive");
+///         Ok(())
+///     }
+/// }
+///
+/// let vars = [("GOSSIP_DELAY_MS".to_owned(), "100".to_owned())];
+/// let args = ["--fanout".to_owned(), "3".to_owned()];
+/// let config = GossipConfig::from_sources(vars, args).unwrap();
+/// assert_eq!((config.delay_ms, config.fanout), (100, 3));
+/// ```
+///
+/// Values are parsed according to the field's type; lists are
+/// comma-separated.
+pub trait Config: Serialize + DeserializeOwned + Default + fmt::Debug {
+    /// The prefix of the environment variables, e.g. `BROADCAST_`.
+    const ENV_PREFIX: &'static str;
+
+    /// Checks the loaded values, e.g. their ranges.
+    fn validate(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Loads and validates the config from the process's environment and
+    /// arguments.
+    fn load() -> Result<Self> {
+        Self::from_sources(std::env::vars(), std::env::args().skip(1))
+    }
+
+    /// Loads and validates the config from the given environment variables
+    /// and arguments.
+    fn from_sources(
+        vars: impl IntoIterator<Item = (String, String)>,
+        args: impl IntoIterator<Item = String>,
+    ) -> Result<Self> {
+        let mut values = BTreeMap::new();
+        for (name, value) in vars {
+            if let Some(field) = name.strip_prefix(Self::ENV_PREFIX) {
+                values.insert(field.to_ascii_lowercase(), value);
+            }
+        }
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            let Some(arg) = arg.strip_prefix("--") else {
+                bail!("unexpected argument {arg:?}");
+            };
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, value.to_owned()),
+                None => (
+                    arg,
+                    args.next()
+                        .ok_or_else(|| anyhow!("missing value for --{arg}"))?,
+                ),
+            };
+            values.insert(name.replace('-', "_"), value);
+        }
+        let config = Self::deserialize(MapDeserializer::new(
+            values.into_iter().map(|(k, v)| (k, ConfigValue(v))),
+        ))
+        .map_err(|err: de::value::Error| anyhow!("invalid configuration: {err}"))?;
+        config.validate().context("invalid configuration")?;
+        Ok(config)
+    }
+}
+
+/// A setting as given by the user, parsed into whatever type is requested.
+struct ConfigValue(String);
+
+impl<'de> IntoDeserializer<'de, de::value::Error> for ConfigValue {
+    type Deserializer = Self;
+
+    fn into_deserializer(self) -> Self {
+        self
+    }
+}
+
+impl ConfigValue {
+    fn parse<T: FromStr>(&self) -> Result<T, de::value::Error>
+    where
+        T::Err: fmt::Display,
+    {
+        self.0
+            .trim()
+            .parse()
+            .map_err(|err| de::Error::custom(format!("{:?}: {err}", self.0)))
+    }
+}
+
+macro_rules! deserialize_parsed {
+    ($($method:ident => $visit:ident,)*) => {
+        $(
+            fn $method<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+                visitor.$visit(self.parse()?)
+            }
+        )*
+    };
+}
+
+impl<'de> Deserializer<'de> for ConfigValue {
+    type Error = de::value::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        visitor.visit_string(self.0)
+    }
+
+    deserialize_parsed! {
+        deserialize_bool => visit_bool,
+        deserialize_i8 => visit_i8,
+        deserialize_i16 => visit_i16,
+        deserialize_i32 => visit_i32,
+        deserialize_i64 => visit_i64,
+        deserialize_u8 => visit_u8,
+        deserialize_u16 => visit_u16,
+        deserialize_u32 => visit_u32,
+        deserialize_u64 => visit_u64,
+        deserialize_f32 => visit_f32,
+        deserialize_f64 => visit_f64,
+        deserialize_char => visit_char,
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        if self.0.is_empty() {
+            visitor.visit_none()
+        } else {
+            visitor.visit_some(self)
+        }
+    }
+
+    fn deserialize_newtype_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_newtype_struct(self)
+    }
+
+    fn deserialize_seq<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, Self::Error> {
+        let items = self
+            .0
+            .split(',')
+            .map(str::trim)
+            .filter(|item| !item.is_empty())
+            .map(|item| ConfigValue(item.to_owned()));
+        visitor.visit_seq(de::value::SeqDeserializer::new(items))
+    }
+
+    fn deserialize_enum<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        _variants: &'static [&'static str],
+        visitor: V,
+    ) -> Result<V::Value, Self::Error> {
+        visitor.visit_enum(self.0.into_deserializer())
+    }
+
+    forward_to_deserialize_any! {
+        i128 u128 str string bytes byte_buf unit unit_struct tuple tuple_struct
+        map struct identifier ignored_any
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Deserialize;
+
+    use super::*;
+
+    #[derive(PartialEq, Debug, Serialize, Deserialize)]
+    #[serde(default, deny_unknown_fields)]
+    struct TestConfig {
+        delay_ms: u64,
+        name: String,
+        enabled: bool,
+        peers: Vec<String>,
+        limit: Option<u32>,
+    }
+
+    impl Default for TestConfig {
+        fn default() -> Self {
+            Self {
+                delay_ms: 10,
+                name: "default".to_owned(),
+                enabled: false,
+                peers: Vec::new(),
+                limit: None,
+            }
+        }
+    }
+
+    impl Config for TestConfig {
+        const ENV_PREFIX: &'static str = "TEST_";
+
+        fn validate(&self) -> Result<()> {
+            anyhow::ensure!(self.delay_ms < 1000, "delay_ms must be below 1000");
+            Ok(())
+        }
+    }
+
+    fn load(vars: &[(&str, &str)], args: &[&str]) -> Result<TestConfig> {
+        TestConfig::from_sources(
+            vars.iter().map(|&(k, v)| (k.to_owned(), v.to_owned())),
+            args.iter().map(|&arg| arg.to_owned()),
+        )
+    }
+
+    #[test]
+    fn arguments_override_variables_and_defaults() {
+        let config = load(
+            &[
+                ("TEST_DELAY_MS", "20"),
+                ("TEST_NAME", "123"),
+                ("OTHER", "x"),
+            ],
+            &["--delay-ms", "30", "--enabled=true", "--peers", "n1, n2"],
+        )
+        .unwrap();
+        assert_eq!(
+            config,
+            TestConfig {
+                delay_ms: 30,
+                name: "123".to_owned(),
+                enabled: true,
+                peers: vec!["n1".to_owned(), "n2".to_owned()],
+                limit: None,
+            }
+        );
+        assert_eq!(load(&[("TEST_LIMIT", "5")], &[]).unwrap().limit, Some(5));
+    }
+
+    #[test]
+    fn invalid_values_are_rejected() {
+        let error = |vars, args| format!("{:#}", load(vars, args).unwrap_err());
+        assert!(error(&[("TEST_DELAY_MS", "soon")], &[]).contains("\"soon\""));
+        assert!(error(&[], &["--delay-ms", "5000"]).contains("must be below 1000"));
+        assert!(error(&[], &["--dlay-ms", "5"]).contains("unknown field `dlay_ms`"));
+        assert!(error(&[], &["--delay-ms"]).contains("missing value"));
+        assert!(error(&[], &["delay"]).contains("unexpected argument"));
+    }
+}
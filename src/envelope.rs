@@ -0,0 +1,218 @@
+use std::{borrow::Cow, fmt};
+
+use anyhow::Result;
+use serde::{
+    de::{
+        value::BorrowedStrDeserializer, DeserializeSeed, IgnoredAny, IntoDeserializer, MapAccess,
+        Visitor,
+    },
+    forward_to_deserialize_any, Deserialize, Deserializer,
+};
+use serde_json::value::RawValue;
+
+use crate::{Message, MessageHeader, MessageId, NodeId};
+
+/// An incoming message with a parsed header and a raw payload.
+///
+/// Parsing an envelope only looks at `src`, `dest` and the body's `msg_id`,
+/// `in_reply_to` and `type`. The payload stays raw JSON until it is
+/// deserialized with [Envelope::payload], possibly borrowing from the
+/// message (e.g. into `&str` fields):
+///
+/// ```
+/// # use fly_into_the_maelstrom::*;
+/// # use serde::Deserialize;
+/// #[derive(Deserialize)]
+/// struct Echo<'a> {
+///     echo: &'a str,
+/// }
+///
+/// let message = r#"{"src":"c1","dest":"n1","body":{"type":"echo","msg_id":1,"echo":"hi"}}"#;
+/// let envelope = Envelope::parse(message).unwrap();
+/// assert_eq!(envelope.message_type(), Some("echo"));
+/// let echo: Echo = envelope.payload().unwrap();
+/// assert_eq!(echo.echo, "hi");
+/// ```
+#[derive(Clone, Debug)]
+pub struct Envelope<'a> {
+    pub header: MessageHeader,
+    message_type: Option<Cow<'a, str>>,
+    body: &'a RawValue,
+    raw: &'a str,
+}
+
+impl<'a> Envelope<'a> {
+    pub fn parse(message: &'a str) -> Result<Self> {
+        #[derive(Deserialize)]
+        struct RawMessage<'a> {
+            src: NodeId,
+            dest: NodeId,
+            #[serde(borrow)]
+            body: &'a RawValue,
+        }
+
+        #[derive(Deserialize)]
+        struct BodyHeader<'a> {
+            msg_id: Option<MessageId>,
+            in_reply_to: Option<MessageId>,
+            #[serde(borrow, rename = "type")]
+            message_type: Option<Cow<'a, str>>,
+        }
+
+        let RawMessage { src, dest, body } = serde_json::from_str(message)?;
+        let BodyHeader {
+            msg_id,
+            in_reply_to,
+            message_type,
+        } = serde_json::from_str(body.get())?;
+        Ok(Self {
+            header: MessageHeader {
+                src,
+                dest,
+                msg_id,
+                in_reply_to,
+            },
+            message_type,
+            body,
+            raw: message,
+        })
+    }
+
+    /// The body's `type`.
+    pub fn message_type(&self) -> Option<&str> {
+        self.message_type.as_deref()
+    }
+
+    /// The whole message as it was parsed.
+    pub fn raw(&self) -> &'a str {
+        self.raw
+    }
+
+    /// Deserializes the payload, i.e. the body without `msg_id` and
+    /// `in_reply_to`.
+    pub fn payload<P: Deserialize<'a>>(&self) -> Result<P> {
+        let mut deserializer = serde_json::Deserializer::from_str(self.body.get());
+        let payload = P::deserialize(PayloadDeserializer(&mut deserializer))?;
+        deserializer.end()?;
+        Ok(payload)
+    }
+
+    /// Deserializes the payload and returns the whole message.
+    pub fn message<P: Deserialize<'a>>(&self) -> Result<Message<P>> {
+        Ok(Message {
+            header: self.header,
+            payload: self.payload()?,
+        })
+    }
+}
+
+/// Deserializes a message body, skipping the fields that belong to
+/// [MessageHeader].
+struct PayloadDeserializer<D>(D);
+
+impl<'de, D: Deserializer<'de>> Deserializer<'de> for PayloadDeserializer<D> {
+    type Error = D::Error;
+
+    fn deserialize_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        self.0.deserialize_any(PayloadVisitor(visitor))
+    }
+
+    // Payloads without fields (e.g. `()`) have to accept any body.
+    fn deserialize_unit<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        self.0.deserialize_ignored_any(IgnoredAny)?;
+        visitor.visit_unit()
+    }
+
+    fn deserialize_unit_struct<V: Visitor<'de>>(
+        self,
+        _name: &'static str,
+        visitor: V,
+    ) -> Result<V::Value, D::Error> {
+        self.deserialize_unit(visitor)
+    }
+
+    fn deserialize_option<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        visitor.visit_some(self)
+    }
+
+    fn deserialize_ignored_any<V: Visitor<'de>>(self, visitor: V) -> Result<V::Value, D::Error> {
+        self.0.deserialize_ignored_any(visitor)
+    }
+
+    forward_to_deserialize_any! {
+        bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+        bytes byte_buf newtype_struct seq tuple tuple_struct map struct enum
+        identifier
+    }
+}
+
+struct PayloadVisitor<V>(V);
+
+impl<'de, V: Visitor<'de>> Visitor<'de> for PayloadVisitor<V> {
+    type Value = V::Value;
+
+    fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        self.0.expecting(f)
+    }
+
+    fn visit_map<A: MapAccess<'de>>(self, map: A) -> Result<V::Value, A::Error> {
+        self.0.visit_map(PayloadMap(map))
+    }
+}
+
+struct PayloadMap<A>(A);
+
+impl<'de, A: MapAccess<'de>> MapAccess<'de> for PayloadMap<A> {
+    type Error = A::Error;
+
+    fn next_key_seed<K: DeserializeSeed<'de>>(
+        &mut self,
+        seed: K,
+    ) -> Result<Option<K::Value>, A::Error> {
+        while let Some(key) = self.0.next_key::<Cow<'de, str>>()? {
+            if key == "msg_id" || key == "in_reply_to" {
+                self.0.next_value::<IgnoredAny>()?;
+                continue;
+            }
+            return match key {
+                Cow::Borrowed(key) => seed.deserialize(BorrowedStrDeserializer::new(key)),
+                Cow::Owned(key) => seed.deserialize(key.into_deserializer()),
+            }
+            .map(Some);
+        }
+        Ok(None)
+    }
+
+    fn next_value_seed<T: DeserializeSeed<'de>>(&mut self, seed: T) -> Result<T::Value, A::Error> {
+        self.0.next_value_seed(seed)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    #[test]
+    fn payload_excludes_header_fields() {
+        let message = r#"{"src":"n1","dest":"c1","body":{"in_reply_to":3,"type":"read_ok","msg_id":7,"value":[1,2]}}"#;
+        let envelope = Envelope::parse(message).unwrap();
+        assert_eq!(envelope.header.msg_id, Some(MessageId::from(7)));
+        assert_eq!(envelope.header.in_reply_to, Some(MessageId::from(3)));
+        assert_eq!(envelope.message_type(), Some("read_ok"));
+        assert_eq!(
+            envelope.payload::<Value>().unwrap(),
+            json!({"type": "read_ok", "value": [1, 2]})
+        );
+        envelope.payload::<()>().unwrap();
+    }
+
+    #[test]
+    fn escaped_keys_and_types_are_unescaped() {
+        let message = r#"{"src":"n1","dest":"c1","body":{"type":"a\"b","k\"ey":1}}"#;
+        let envelope = Envelope::parse(message).unwrap();
+        assert_eq!(envelope.message_type(), Some("a\"b"));
+        assert_eq!(envelope.payload::<Value>().unwrap()["k\"ey"], 1);
+    }
+}
@@ -0,0 +1,24 @@
+use crate::NodeState;
+
+/// The outcome of handling a node's input.
+///
+/// `Ok(None)` keeps the current state, `Ok(Some(state))` continues with
+/// another one. Errors keep the current state as well, whether the node keeps
+/// running depends on its [ErrorPolicy].
+pub type Transition = anyhow::Result<Option<Box<dyn NodeState>>>;
+
+/// What to do when handling a node's input fails.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum ErrorPolicy {
+    /// Log the error and continue with the node's state.
+    Log,
+    /// Like [ErrorPolicy::Log], but also reply to the request that caused the
+    /// error (if any) with an error message.
+    ///
+    /// The error code is taken from the error if it is an
+    /// [crate::ErrorPayload] and [crate::ErrorCode::Crash] otherwise.
+    ReplyError,
+    /// Stop the node.
+    #[default]
+    Crash,
+}
@@ -1,46 +1,107 @@
-use std::sync::mpsc;
+use std::{
+    mem,
+    sync::{Arc, Mutex},
+};
 
-use anyhow::Result;
 use serde::{Deserialize, Serialize};
 
-use crate::{deserialize_message, Message, MessageTransmitter, NodeId, NodeState};
+use crate::{
+    error_reply, output::OutputSender, reply_without_id, rpc::PendingRpcs, Envelope, ErrorCode,
+    ErrorPayload, Logger, MessageTransmitter, NodeId, NodeState, Timers,
+};
 
 /// Returns the state after the node was successfully initialized.
+///
+/// Errors (e.g. an invalid configuration) are replied to the `init` message
+/// and stop the node.
 pub type AfterInitTransition =
-    Box<dyn Fn(InitPayload, MessageTransmitter<()>) -> Box<dyn NodeState>>;
+    Box<dyn Fn(InitPayload, MessageTransmitter<()>) -> anyhow::Result<Box<dyn NodeState>>>;
 
+/// Like [AfterInitTransition], with the node's [crate::Config].
+pub type ConfiguredAfterInitTransition<C> =
+    Box<dyn Fn(&C, InitPayload, MessageTransmitter<()>) -> anyhow::Result<Box<dyn NodeState>>>;
+
+/// A node that just handled its `init` message.
+pub(crate) struct Initialized {
+    pub(crate) node: Box<dyn NodeState>,
+    /// Messages received before `init`, to be handled by `node`.
+    pub(crate) deferred: Vec<String>,
+}
+
+/// Stands in for a node until its `init` message arrives.
 pub(crate) struct InitializingNode {
-    stdout_tx: mpsc::SyncSender<String>,
+    output_tx: OutputSender,
+    rpcs: Arc<Mutex<PendingRpcs>>,
+    timers: Timers,
     after_init: AfterInitTransition,
+    logger: Arc<Logger>,
+    /// Messages received so far.
+    pending: Vec<String>,
 }
 
 impl InitializingNode {
     pub(crate) fn new(
-        stdout_tx: mpsc::SyncSender<String>,
+        output_tx: OutputSender,
+        rpcs: Arc<Mutex<PendingRpcs>>,
+        timers: Timers,
         after_init: AfterInitTransition,
+        logger: Arc<Logger>,
     ) -> Self {
         Self {
-            stdout_tx,
+            output_tx,
+            rpcs,
+            timers,
             after_init,
+            logger,
+            pending: Vec::new(),
         }
     }
-}
-
-impl NodeState for InitializingNode {
-    fn handle(self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>> {
-        let init_message: Message<RequestPayload> = deserialize_message(request)?;
-
-        let Message { header, payload } = init_message;
-        let RequestPayload::Init(data) = payload;
 
-        let mut tx = MessageTransmitter::new(data.node_id, self.stdout_tx);
-        tx.reply(&header, ResponsePayload::InitOk);
-
-        Ok((self.after_init)(data, tx.into()))
-    }
-
-    fn wake_up(self: Box<Self>) -> Result<Box<dyn NodeState>> {
-        Ok(self)
+    /// Handles `message` (with its `envelope`, if it is a message at all).
+    ///
+    /// Returns the initialized node once `message` is the `init` message,
+    /// everything else is kept for that node. Errors are fatal, there is no
+    /// node to continue with.
+    pub(crate) fn handle(
+        &mut self,
+        message: &str,
+        envelope: Option<&Envelope>,
+    ) -> anyhow::Result<Option<Initialized>> {
+        let Some(request) = envelope.filter(|request| request.message_type() == Some("init"))
+        else {
+            self.pending.push(message.to_owned());
+            return Ok(None);
+        };
+        let header = request.header.request()?;
+        let RequestPayload::Init(data) = request.payload()?;
+        self.logger.set_node_id(data.node_id);
+
+        let output_tx = self.output_tx.clone();
+        let tx = MessageTransmitter::new(
+            data.node_id,
+            self.output_tx.clone(),
+            Arc::clone(&self.rpcs),
+            self.timers.clone(),
+        );
+        let node = match (self.after_init)(data, tx) {
+            Ok(node) => node,
+            Err(error) => {
+                let error = error.context("failed to initialize node");
+                output_tx.send(&error_reply(
+                    &header,
+                    ErrorPayload {
+                        code: ErrorCode::Crash,
+                        text: format!("{error:#}"),
+                    },
+                ));
+                return Err(error);
+            }
+        };
+        output_tx.send(&reply_without_id(&header, ResponsePayload::InitOk));
+        Ok(Some(Initialized {
+            node,
+            deferred: mem::take(&mut self.pending),
+        }))
     }
 }
 
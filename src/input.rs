@@ -1,108 +1,210 @@
 use std::{
-    io::BufRead as _,
+    io::BufRead,
     sync::{
-        atomic::{AtomicBool, Ordering},
-        mpsc, Arc,
+        mpsc::{self, RecvTimeoutError},
+        Arc,
     },
-    thread::{sleep, JoinHandle},
-    time::{Duration, Instant},
+    time::Instant,
 };
 
-use crate::Logger;
+use signal_hook::{
+    consts::{SIGINT, SIGTERM},
+    iterator::{Handle, Signals},
+};
+
+use crate::{Level, Logger};
+
+/// How [crate::run_node] reads the node's input.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct InputOptions {
+    /// How many inputs (messages, wake ups and signals) may wait for the node
+    /// before reading more messages blocks (default: 100).
+    pub capacity: usize,
+}
+
+impl Default for InputOptions {
+    fn default() -> Self {
+        Self { capacity: 100 }
+    }
+}
 
 pub(crate) enum NodeInput {
     Message(String),
     WakeUp,
+    /// The input was closed, the output failed or the process received
+    /// SIGTERM/SIGINT.
+    Shutdown,
+}
+
+/// The channels to and from the input threads.
+pub(crate) struct InputChannels {
+    /// Receives the node's inputs.
+    pub(crate) node_rx: mpsc::Receiver<NodeInput>,
+    /// Another sender of inputs, e.g. to shut the node down.
+    pub(crate) node_tx: mpsc::SyncSender<NodeInput>,
+    /// Requests wake ups, see [wake_up_handler].
+    pub(crate) wake_up_tx: mpsc::SyncSender<Option<Instant>>,
+    /// Stops the [signal_handler], if any.
+    pub(crate) signals: Option<Handle>,
 }
 
+/// Spawns the threads reading from `reader` and handling wake ups, and, if
+/// `handle_signals` is set, the thread waiting for SIGTERM/SIGINT.
 pub(crate) fn spawn_input_threads(
+    reader: impl BufRead + Send + 'static,
+    options: InputOptions,
+    handle_signals: bool,
     logger: Arc<Logger>,
-) -> (mpsc::Receiver<NodeInput>, mpsc::SyncSender<Option<Instant>>) {
-    let (node_tx, node_rx) = mpsc::sync_channel::<NodeInput>(100);
-    let (wake_up_tx, wake_up_rx) = mpsc::sync_channel::<Option<Instant>>(100);
+) -> InputChannels {
+    let (node_tx, node_rx) = mpsc::sync_channel::<NodeInput>(options.capacity);
+    let (wake_up_tx, wake_up_rx) = mpsc::sync_channel::<Option<Instant>>(options.capacity);
     std::thread::spawn({
         let node_tx = node_tx.clone();
         let logger = Arc::clone(&logger);
-        move || stdin_reader(node_tx, logger)
+        move || input_reader(reader, node_tx, logger)
+    });
+    let signals = handle_signals.then(|| {
+        // Registering before spawning makes sure no signal is missed once the
+        // node runs.
+        let signals =
+            Signals::new([SIGTERM, SIGINT]).expect("registering signal handlers should succeed");
+        let handle = signals.handle();
+        std::thread::spawn({
+            let node_tx = node_tx.clone();
+            let logger = Arc::clone(&logger);
+            move || signal_handler(signals, node_tx, logger)
+        });
+        handle
+    });
+    std::thread::spawn({
+        let node_tx = node_tx.clone();
+        move || wake_up_handler(wake_up_rx, node_tx, logger)
     });
-    std::thread::spawn(move || wake_up_handler(wake_up_rx, node_tx, logger));
-    (node_rx, wake_up_tx)
+    InputChannels {
+        node_rx,
+        node_tx,
+        wake_up_tx,
+        signals,
+    }
+}
+
+fn input_reader(reader: impl BufRead, node_tx: mpsc::SyncSender<NodeInput>, logger: Arc<Logger>) {
+    for line in reader.lines() {
+        let line = match line {
+            Ok(line) => line,
+            Err(error) => {
+                let text = format!("failed to read input, shutting down: {error}");
+                logger.log(Level::Warn, module_path!(), &text);
+                break;
+            }
+        };
+        logger.log_message('<', &line);
+        if node_tx.send(NodeInput::Message(line)).is_err() {
+            // The node shut down (e.g. because its output was closed).
+            return;
+        }
+    }
+    logger.log(Level::Info, module_path!(), "< EOF");
+    // The node might have shut down already.
+    let _ = node_tx.send(NodeInput::Shutdown);
 }
 
-fn stdin_reader(node_tx: mpsc::SyncSender<NodeInput>, logger: Arc<Logger>) {
-    let lines = std::io::stdin().lock().lines();
-    for line in lines {
-        let line = line.expect("reading from stdin should succeed");
-        logger.log(&format!("< {line}"));
-        node_tx
-            .send(NodeInput::Message(line))
-            .expect("sending to channel should succeed");
+/// Sends [NodeInput::Shutdown] on SIGTERM or SIGINT.
+///
+/// Returns without sending anything once the signals' [Handle] is closed.
+fn signal_handler(mut signals: Signals, node_tx: mpsc::SyncSender<NodeInput>, logger: Arc<Logger>) {
+    if let Some(signal) = signals.forever().next() {
+        logger.log(Level::Info, module_path!(), &format!("< SIGNAL {signal}"));
+        let _ = node_tx.send(NodeInput::Shutdown);
     }
 }
 
+/// Sends [NodeInput::WakeUp] at the latest requested instant.
+///
+/// This is the only timer thread: it waits for new requests and the current
+/// deadline at the same time using [mpsc::Receiver::recv_timeout]. A new
+/// request replaces the previous one, `None` cancels it.
 fn wake_up_handler(
     wake_up_rx: mpsc::Receiver<Option<Instant>>,
     node_tx: mpsc::SyncSender<NodeInput>,
     logger: Arc<Logger>,
 ) {
-    let mut waker: Option<WakerHandle> = None;
+    let mut next_wake_up: Option<Instant> = None;
     loop {
-        let next_wake_up = wake_up_rx
-            .recv()
-            .expect("reading from channel should succeed");
-        waker.take();
-
-        if let Some(instant) = next_wake_up {
-            let sleep_duration = instant - Instant::now();
-            if sleep_duration > Duration::ZERO {
-                waker = Some(spawn_waker_thread(
-                    sleep_duration,
-                    node_tx.clone(),
-                    Arc::clone(&logger),
-                ));
-            } else {
-                node_tx
-                    .send(NodeInput::WakeUp)
-                    .expect("sending wake up should succeed");
+        let received = match next_wake_up {
+            None => wake_up_rx
+                .recv()
+                .map_err(|_| RecvTimeoutError::Disconnected),
+            Some(instant) => {
+                let now = Instant::now();
+                if instant <= now {
+                    Err(RecvTimeoutError::Timeout)
+                } else {
+                    wake_up_rx.recv_timeout(instant - now)
+                }
             }
-        }
-    }
-}
-
-fn spawn_waker_thread(
-    sleep_duration: Duration,
-    tx: mpsc::SyncSender<NodeInput>,
-    logger: Arc<Logger>,
-) -> WakerHandle {
-    let active = Arc::new(AtomicBool::new(true));
-    let handle = std::thread::spawn({
-        let active = Arc::clone(&active);
-        move || {
-            sleep(sleep_duration);
-            if active.load(Ordering::Relaxed) {
-                logger.log("< WAKE UP");
-                tx.send(NodeInput::WakeUp)
-                    .expect("sending wake up should succeed");
-            } else {
-                logger.log(": (wake up was cancelled)");
+        };
+        match received {
+            Ok(instant) => next_wake_up = instant,
+            Err(RecvTimeoutError::Timeout) => {
+                next_wake_up = None;
+                logger.log(Level::Trace, module_path!(), "< WAKE UP");
+                if node_tx.send(NodeInput::WakeUp).is_err() {
+                    // The node shut down.
+                    return;
+                }
             }
+            Err(RecvTimeoutError::Disconnected) => return,
         }
-    });
-    WakerHandle {
-        _handle: handle,
-        active,
     }
 }
 
-struct WakerHandle {
-    _handle: JoinHandle<()>,
-    active: Arc<AtomicBool>,
-}
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
 
-impl Drop for WakerHandle {
-    fn drop(&mut self) {
-        // Dropping its JoinHandle does not stop the waker thread. We set
-        // active to false to stop it from sending a wake up signal.
-        self.active.store(false, Ordering::Relaxed);
+    #[test]
+    fn wake_up_handler_sends_latest_wake_up() {
+        let (node_tx, node_rx) = mpsc::sync_channel(100);
+        let (wake_up_tx, wake_up_rx) = mpsc::sync_channel(100);
+        let start = Instant::now();
+        wake_up_tx
+            .send(Some(start + Duration::from_secs(60)))
+            .unwrap();
+        wake_up_tx.send(None).unwrap();
+        wake_up_tx
+            .send(Some(start + Duration::from_millis(10)))
+            .unwrap();
+        std::thread::spawn(move || wake_up_handler(wake_up_rx, node_tx, Arc::default()));
+
+        let input = node_rx.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(matches!(input, NodeInput::WakeUp));
+        assert!(start.elapsed() >= Duration::from_millis(10));
+        drop(wake_up_tx);
+        assert!(node_rx.recv().is_err());
+    }
+
+    /// Regression test: waiting for wake ups used to spawn one sleeping thread
+    /// per request.
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn wake_up_handler_does_not_spawn_threads() {
+        let thread_count = || std::fs::read_dir("/proc/self/task").unwrap().count();
+        let (node_tx, _node_rx) = mpsc::sync_channel(100);
+        let (wake_up_tx, wake_up_rx) = mpsc::sync_channel(100);
+        let before = thread_count();
+        for secs in 0..100 {
+            wake_up_tx
+                .send(Some(Instant::now() + Duration::from_secs(60 + secs)))
+                .unwrap();
+        }
+        drop(wake_up_tx);
+        // Runs on the current thread and returns once all requests are
+        // processed.
+        wake_up_handler(wake_up_rx, node_tx, Arc::default());
+        // Allow for some noise caused by tests running in parallel.
+        assert!(thread_count() < before + 10);
     }
 }
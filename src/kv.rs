@@ -0,0 +1,322 @@
+//! A client for Maelstrom's key-value services.
+//!
+//! See <https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md>.
+//!
+//! [KvClient] sends `read`, `write` and `cas` requests as RPCs (see
+//! [MessageTransmitter::call]). Each returns a [KvCall], which recognizes the
+//! matching [RpcResponse] in [crate::NodeState::rpc_reply] and parses it:
+//!
+//! ```
+//! # use fly_into_the_maelstrom::{kv::*, *};
+//! struct Node {
+//!     tx: MessageTransmitter<()>,
+//!     kv: KvClient<String, u64>,
+//!     pending_read: Option<KvCall<u64>>,
+//! }
+//!
+//! impl NodeState for Node {
+//! #   fn handle(&mut self, _: &str) -> Transition {
+//! #       Ok(None)
+//! #   }
+//!     fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+//!         if let Some(read) = self.pending_read.take_if(|read| read.matches(&response)) {
+//!             match read.parse(response.result) {
+//!                 Ok(value) => println!("read {value}"),
+//!                 Err(KvError::KeyDoesNotExist) => println!("nothing there yet"),
+//!                 Err(err) => return Err(err.into()),
+//!             }
+//!         }
+//!         Ok(None)
+//!     }
+//! }
+//! ```
+
+use std::{fmt, future::Future, marker::PhantomData, time::Duration};
+
+use serde::{
+    de::{DeserializeOwned, IntoDeserializer},
+    Deserialize, Serialize,
+};
+
+use crate::{
+    async_node::Context, ErrorCode, ErrorPayload, MessageId, MessageTransmitter, NodeId,
+    RpcResponse, RpcResult, LIN_KV, LWW_KV, SEQ_KV,
+};
+
+/// Sends requests to a key-value service.
+///
+/// Keys and values can be any type serde can handle, as long as it matches
+/// what is stored in the service.
+pub struct KvClient<K, V> {
+    service: NodeId,
+    _types: PhantomData<fn(K, V)>,
+}
+
+impl<K, V> Clone for KvClient<K, V> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<K, V> Copy for KvClient<K, V> {}
+
+impl<K, V> fmt::Debug for KvClient<K, V> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KvClient")
+            .field("service", &self.service)
+            .finish()
+    }
+}
+
+impl<K, V> KvClient<K, V>
+where
+    K: Serialize,
+    V: Serialize + DeserializeOwned,
+{
+    /// Creates a client for the service with the given node id.
+    pub fn new(service: NodeId) -> Self {
+        Self {
+            service,
+            _types: PhantomData,
+        }
+    }
+
+    /// Creates a client for `seq-kv`.
+    pub fn seq() -> Self {
+        Self::new(SEQ_KV)
+    }
+
+    /// Creates a client for `lin-kv`.
+    pub fn lin() -> Self {
+        Self::new(LIN_KV)
+    }
+
+    /// Creates a client for `lww-kv`.
+    pub fn lww() -> Self {
+        Self::new(LWW_KV)
+    }
+
+    /// The node id of the service.
+    pub fn service(&self) -> NodeId {
+        self.service
+    }
+
+    /// Reads the value of `key`.
+    ///
+    /// Fails with [KvError::KeyDoesNotExist] if there is no such key.
+    pub fn read<P>(
+        &self,
+        tx: &mut MessageTransmitter<P>,
+        key: K,
+        timeout: Option<Duration>,
+    ) -> KvCall<V>
+    where
+        P: Serialize,
+    {
+        self.call(tx, KvRequest::Read { key }, timeout)
+    }
+
+    /// Sets the value of `key`.
+    pub fn write<P>(
+        &self,
+        tx: &mut MessageTransmitter<P>,
+        key: K,
+        value: V,
+        timeout: Option<Duration>,
+    ) -> KvCall<()>
+    where
+        P: Serialize,
+    {
+        self.call(tx, KvRequest::Write { key, value }, timeout)
+    }
+
+    /// Sets the value of `key` to `to` if it currently is `from`.
+    ///
+    /// Fails with [KvError::PreconditionFailed] if the value is different and
+    /// with [KvError::KeyDoesNotExist] if there is no such key, unless
+    /// `create_if_not_exists` is set.
+    ///
+    /// Note that a timed out compare-and-swap may or may not have been applied.
+    pub fn cas<P>(
+        &self,
+        tx: &mut MessageTransmitter<P>,
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+        timeout: Option<Duration>,
+    ) -> KvCall<()>
+    where
+        P: Serialize,
+    {
+        let request = KvRequest::Cas {
+            key,
+            from,
+            to,
+            create_if_not_exists,
+        };
+        self.call(tx, request, timeout)
+    }
+
+    fn call<P, T>(
+        &self,
+        tx: &mut MessageTransmitter<P>,
+        request: KvRequest<K, V>,
+        timeout: Option<Duration>,
+    ) -> KvCall<T>
+    where
+        P: Serialize,
+    {
+        KvCall {
+            request_id: tx.call_any(self.service, request, timeout),
+            _value: PhantomData,
+        }
+    }
+}
+
+/// A pending request to a key-value service.
+///
+/// `T` is the type of a successful reply's value.
+pub struct KvCall<T> {
+    request_id: MessageId,
+    _value: PhantomData<fn() -> T>,
+}
+
+impl<T> fmt::Debug for KvCall<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("KvCall")
+            .field("request_id", &self.request_id)
+            .finish()
+    }
+}
+
+impl<T> KvCall<T> {
+    /// The [MessageId] of the request.
+    pub fn request_id(&self) -> MessageId {
+        self.request_id
+    }
+
+    /// Whether `response` is the outcome of this request.
+    pub fn matches(&self, response: &RpcResponse) -> bool {
+        response.request_id == self.request_id
+    }
+}
+
+impl<T: DeserializeOwned> KvCall<T> {
+    /// Turns the outcome of this request into its value.
+    pub fn parse(&self, result: RpcResult) -> Result<T, KvError> {
+        let invalid_reply = |err: anyhow::Error| KvError::InvalidReply(err.to_string());
+        match result?
+            .deserialize::<KvReply<T>>()
+            .map_err(invalid_reply)?
+            .payload
+        {
+            KvReply::ReadOk { value } => Ok(value),
+            // These carry no value, which only deserializes into `()`.
+            KvReply::WriteOk | KvReply::CasOk => T::deserialize(().into_deserializer())
+                .map_err(|err: serde::de::value::Error| KvError::InvalidReply(err.to_string())),
+        }
+    }
+}
+
+impl<T: DeserializeOwned + 'static> KvCall<T> {
+    /// Waits for the outcome of this request in an [crate::async_node]
+    /// handler.
+    ///
+    /// Call this right after sending the request (see [Context::response]).
+    pub fn wait(self, ctx: &Context) -> impl Future<Output = Result<T, KvError>> {
+        let response = ctx.response(self.request_id);
+        async move { self.parse(response.await) }
+    }
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KvRequest<K, V> {
+    Read {
+        key: K,
+    },
+    Write {
+        key: K,
+        value: V,
+    },
+    Cas {
+        key: K,
+        from: V,
+        to: V,
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum KvReply<V> {
+    ReadOk { value: V },
+    WriteOk,
+    CasOk,
+}
+
+/// Why a request to a key-value service failed.
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub enum KvError {
+    /// The key does not exist.
+    KeyDoesNotExist,
+    /// A compare-and-swap found a different value.
+    PreconditionFailed,
+    /// There was no reply in time. The request may or may not have been
+    /// applied.
+    Timeout,
+    /// Any other error the service replied with.
+    Service(ErrorPayload),
+    /// The reply could not be parsed.
+    InvalidReply(String),
+}
+
+impl KvError {
+    /// The [ErrorCode] corresponding to this error, e.g. for replying to a
+    /// client.
+    pub fn code(&self) -> ErrorCode {
+        match self {
+            KvError::KeyDoesNotExist => ErrorCode::KeyDoesNotExist,
+            KvError::PreconditionFailed => ErrorCode::PreconditionFailed,
+            KvError::Timeout => ErrorCode::Timeout,
+            KvError::Service(error) => error.code,
+            KvError::InvalidReply(_) => ErrorCode::Crash,
+        }
+    }
+}
+
+impl From<ErrorPayload> for KvError {
+    fn from(error: ErrorPayload) -> Self {
+        match error.code {
+            ErrorCode::KeyDoesNotExist => KvError::KeyDoesNotExist,
+            ErrorCode::PreconditionFailed => KvError::PreconditionFailed,
+            ErrorCode::Timeout => KvError::Timeout,
+            _ => KvError::Service(error),
+        }
+    }
+}
+
+impl From<KvError> for ErrorPayload {
+    fn from(error: KvError) -> Self {
+        ErrorPayload {
+            code: error.code(),
+            text: error.to_string(),
+        }
+    }
+}
+
+impl fmt::Display for KvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            KvError::KeyDoesNotExist => write!(f, "key does not exist"),
+            KvError::PreconditionFailed => write!(f, "precondition failed"),
+            KvError::Timeout => write!(f, "timed out"),
+            KvError::Service(error) => write!(f, "{error}"),
+            KvError::InvalidReply(err) => write!(f, "invalid reply: {err}"),
+        }
+    }
+}
+
+impl std::error::Error for KvError {}
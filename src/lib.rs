@@ -11,7 +11,6 @@
 //! spoil and we can use it as an example:
 //!
 //! ```no_run
-//! use anyhow::Result;
 //! use fly_into_the_maelstrom::*;
 //! use serde::{Deserialize, Serialize};
 //!
@@ -33,42 +32,76 @@
 //! }
 //!
 //! impl NodeState for EchoNode {
-//!     fn handle(mut self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>> {
+//!     fn handle(&mut self, request: &str) -> Transition {
 //!         let Message {
 //!             header,
 //!             payload: RequestPayload::Echo { echo },
 //!         } = deserialize_message(request)?;
-//!         self.tx.reply(&header, ResponsePayload::EchoOk { echo });
-//!         Ok(self)
-//!     }
-//!
-//!     fn wake_up(self: Box<Self>) -> Result<Box<dyn NodeState>> {
-//!         Ok(self)
+//!         self.tx.reply(&header.request()?, ResponsePayload::EchoOk { echo });
+//!         Ok(None)
 //!     }
 //! }
 //!
 //! fn main() -> anyhow::Result<()> {
-//!     run_node(Box::new(|_, tx| Box::new(EchoNode { tx: tx.into() })))
+//!     run_node(Box::new(|_, tx| Ok(Box::new(EchoNode { tx: tx.into() }))))
 //! }
 //! ```
 
+pub mod async_node;
+mod clock;
+mod cluster;
+mod config;
+mod envelope;
+mod error;
 mod init;
 mod input;
+pub mod kv;
 mod logging;
 mod message;
+mod metrics;
 mod node_id;
 mod output;
+mod rng;
+mod rpc;
+mod runtime;
+pub mod services;
+pub mod sim;
+mod timer;
+pub mod topology;
+pub mod trace;
+pub mod transport;
+mod typed;
 
-use std::{panic, process, sync::Arc, time::Instant};
+use std::{
+    panic,
+    path::PathBuf,
+    process,
+    sync::{Arc, Once},
+    time::Instant,
+};
 
-use anyhow::Result;
+use anyhow::anyhow;
 
+pub use clock::now;
+pub use cluster::Cluster;
+pub use config::Config;
+pub use envelope::Envelope;
+pub use error::{ErrorPolicy, Transition};
 pub use init::*;
-use input::{spawn_input_threads, NodeInput};
+pub use input::InputOptions;
+use input::{spawn_input_threads, InputChannels, NodeInput};
 pub use logging::*;
 pub use message::*;
+pub use metrics::{Histogram, MessageCounts, NodeMetrics};
 pub use node_id::*;
 use output::spawn_output_thread;
+pub use output::{Backpressure, FlushPolicy, OutputOptions};
+pub use rpc::{RpcCallback, RpcReply, RpcResponse, RpcResult};
+use runtime::Runtime;
+pub use timer::{TimerId, Timers};
+use trace::Tracer;
+use transport::{Stdio, Transport};
+pub use typed::TypedNodeState;
 
 /// A node's state (as in state machine).
 pub trait NodeState {
@@ -76,55 +109,224 @@ pub trait NodeState {
     ///
     /// Typically you want to [deserialize_message] the `request`, match on its
     /// payload and then send one or more messages using [MessageTransmitter].
-    fn handle(self: Box<Self>, request: &str) -> Result<Box<dyn NodeState>>;
+    /// Implement [TypedNodeState] instead to have this done for you.
+    ///
+    /// Replies to requests sent with [MessageTransmitter::call] are passed to
+    /// [NodeState::rpc_reply] instead.
+    ///
+    /// Errors keep the current state, whether the node continues depends on
+    /// its [ErrorPolicy].
+    fn handle(&mut self, request: &str) -> Transition;
 
-    /// Handles a previously scheduled wake up call.
-    fn wake_up(self: Box<Self>) -> Result<Box<dyn NodeState>>;
+    /// Handles an incoming message whose header was already parsed.
+    ///
+    /// [run_node] calls this instead of [NodeState::handle] for everything
+    /// that parses as an [Envelope], so overriding it saves parsing the
+    /// header twice. The default implementation calls [NodeState::handle]
+    /// with the raw message.
+    fn handle_envelope(&mut self, request: Envelope<'_>) -> Transition {
+        self.handle(request.raw())
+    }
+
+    /// Handles an expired timer.
+    ///
+    /// `timer` is either an identifier returned by [Timers] or
+    /// [TimerId::NEXT_WAKE_UP] for the timer requested via
+    /// [NodeState::next_wake_up]. The default implementation ignores it.
+    fn wake_up(&mut self, timer: TimerId) -> Transition {
+        let _ = timer;
+        Ok(None)
+    }
+
+    /// Handles the outcome of a request sent with [MessageTransmitter::call].
+    ///
+    /// The default implementation ignores it.
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        let _ = response;
+        Ok(None)
+    }
 
     /// Requests or cancels a wake up call.
     ///
     /// [run_node] will call this method *after* each call to
     /// [NodeState::handle] or [NodeState::wake_up] and will call
-    /// [NodeState::wake_up] shortly after the returned `Instant`.
+    /// [NodeState::wake_up] with [TimerId::NEXT_WAKE_UP] shortly after the
+    /// returned `Instant`.
     ///
-    /// There is at most one active wake up timer. Returning `None` cancels any
+    /// There is at most one such wake up timer. Returning `None` cancels any
     /// active timer. This means you have to repeatedly return a timestamp if
-    /// you still want the wakeup to happen.
+    /// you still want the wakeup to happen. Use [Timers] if you need more than
+    /// one timer or periodic timers.
     ///
     /// Example: If you want either [NodeState::handle]/[NodeState::wake_up] to
-    /// be called *at least every 200ms*, just return `Some(Instant::now() +
+    /// be called *at least every 200ms*, just return `Some(now() +
     /// Duration::from_millis(200))` every time.
     fn next_wake_up(&self) -> Option<Instant> {
         None
     }
+
+    /// Called once before the node stops because STDIN was closed or the
+    /// process received SIGTERM/SIGINT.
+    ///
+    /// Messages sent from here are still written before the process exits.
+    /// The default implementation does nothing.
+    fn on_shutdown(&mut self) {}
+}
+
+/// Parameters of [run_node_with].
+#[derive(PartialEq, Eq, Clone, Debug, Default)]
+pub struct NodeOptions {
+    /// What to do when the node fails to handle its input.
+    pub error_policy: ErrorPolicy,
+    /// Where to record a trace of the node's inputs and outputs, see
+    /// [trace].
+    ///
+    /// Defaults to `$MAELSTROM_TRACE_DIR/<pid>.jsonl` if that variable is set.
+    pub trace: Option<PathBuf>,
+    /// Whether to answer requests of `type` `metrics` with the node's
+    /// [NodeMetrics] (in a `metrics_ok` reply) instead of passing them to the
+    /// node.
+    pub answer_metrics_requests: bool,
+    /// How STDIN is read.
+    pub input: InputOptions,
+    /// How messages are written to STDOUT.
+    pub output: OutputOptions,
 }
 
 /// Runs the main loop.
 ///
-/// This will spawn three long-running threads for (1) reading from STDIN, (2)
-/// writing to STDOUT and (3) handling wake-up requests from the node.
+/// This will spawn four long-running threads for (1) reading from STDIN, (2)
+/// writing to STDOUT, (3) handling wake-up requests from the node and (4)
+/// waiting for SIGTERM/SIGINT.
+///
+/// The node is created by `after_init` once the `init` message arrived.
+/// Messages received before are handled right after that, further `init`
+/// messages are answered with an error.
+///
+/// Returns `Ok` after STDIN or STDOUT was closed or a signal was received,
+/// once [NodeState::on_shutdown] was called and all messages were written (if
+/// STDOUT is still open). Note that this waits for all [MessageTransmitter]s
+/// to be dropped, so don't move them to other threads.
 pub fn run_node(after_init: AfterInitTransition) -> anyhow::Result<()> {
+    run_node_with(NodeOptions::default(), after_init)
+}
+
+/// Like [run_node], but loads a [Config] first and passes it to
+/// `after_init`.
+///
+/// The config is logged at startup. Fails right away if it is invalid.
+pub fn run_configured_node<C: Config + 'static>(
+    after_init: ConfiguredAfterInitTransition<C>,
+) -> anyhow::Result<()> {
+    let config = C::load()?;
+    Logger::from_env().log(
+        Level::Info,
+        module_path!(),
+        &format!("config: {}", serde_json::to_string(&config)?),
+    );
+    run_node(Box::new(move |init, tx| after_init(&config, init, tx)))
+}
+
+/// Like [run_node], but with non-default [NodeOptions].
+///
+/// As the node is the whole process, a panic in any thread exits it.
+pub fn run_node_with(options: NodeOptions, after_init: AfterInitTransition) -> anyhow::Result<()> {
     set_up_panic_handler();
-    let logger = Arc::new(Logger::default());
+    run(Stdio, options, true, after_init)
+}
+
+/// Like [run_node_with], but reads and writes messages using `transport`
+/// instead of STDIN and STDOUT.
+///
+/// This runs a node e.g. on a socket or, with a
+/// [transport::MemoryTransport], inside another program. See [transport].
+/// The node leaves signals and panics to that program: it doesn't stop on
+/// SIGTERM/SIGINT and a panic only ends the thread it happened in.
+pub fn run_node_on(
+    transport: impl Transport,
+    options: NodeOptions,
+    after_init: AfterInitTransition,
+) -> anyhow::Result<()> {
+    run(transport, options, false, after_init)
+}
 
-    let (node_rx, wake_up_tx) = spawn_input_threads(Arc::clone(&logger));
-    let stdout_tx = spawn_output_thread(Arc::clone(&logger));
+fn run(
+    transport: impl Transport,
+    options: NodeOptions,
+    handle_signals: bool,
+    after_init: AfterInitTransition,
+) -> anyhow::Result<()> {
+    let (reader, writer) = transport.split()?;
+    let logger = Arc::new(Logger::from_env());
+    logging::install_log_bridge(Arc::clone(&logger));
 
-    let mut node: Box<dyn NodeState> = Box::new(InitializingNode::new(stdout_tx, after_init));
+    let InputChannels {
+        node_rx,
+        node_tx,
+        wake_up_tx,
+        signals,
+    } = spawn_input_threads(reader, options.input, handle_signals, Arc::clone(&logger));
+    let (stdout_tx, output_thread) =
+        spawn_output_thread(writer, options.output, node_tx, Arc::clone(&logger));
+    let (output_tx, mut tracer) = match options.trace.or_else(trace::path_from_env) {
+        Some(path) => {
+            let (output_tx, tracer) = Tracer::create(&path, stdout_tx)?;
+            (output_tx, Some(tracer))
+        }
+        None => (stdout_tx, None),
+    };
+
+    let mut runtime = Runtime::new(output_tx, after_init, options.error_policy, logger);
+    if options.answer_metrics_requests {
+        runtime.answer_metrics_requests();
+    }
     loop {
-        node = match node_rx.recv()? {
-            NodeInput::Message(message) => node.handle(&message)?,
-            NodeInput::WakeUp => node.wake_up()?,
+        let input = node_rx.recv()?;
+        let _now = tracer
+            .as_mut()
+            .map(|t| t.record_input(&input))
+            .transpose()?;
+        let shutdown = matches!(input, NodeInput::Shutdown);
+        let handled = match input {
+            NodeInput::Message(message) => runtime.handle_message(&message),
+            NodeInput::WakeUp => runtime.wake_up(),
+            NodeInput::Shutdown => {
+                runtime.shutdown();
+                Ok(())
+            }
         };
-        wake_up_tx.send(node.next_wake_up())?;
+        // Record what the node sent before it failed, too.
+        if let Some(tracer) = &mut tracer {
+            tracer.forward_output()?;
+        }
+        handled?;
+        if shutdown {
+            break;
+        }
+        wake_up_tx.send(runtime.next_wake_up())?;
+    }
+
+    // Dropping the runtime (and with it the node) and the tracer closes the
+    // output channel and dropping `wake_up_tx` stops the timer thread.
+    drop(runtime);
+    drop(tracer);
+    drop(wake_up_tx);
+    if let Some(signals) = signals {
+        signals.close();
     }
+    output_thread
+        .join()
+        .map_err(|_| anyhow!("output thread panicked"))
 }
 
 /// Exit the whole process when a thread panics.
 fn set_up_panic_handler() {
-    let orig_hook = panic::take_hook();
-    panic::set_hook(Box::new(move |panic_info| {
-        orig_hook(panic_info);
-        process::exit(1);
-    }));
+    static SET_UP: Once = Once::new();
+    SET_UP.call_once(|| {
+        let orig_hook = panic::take_hook();
+        panic::set_hook(Box::new(move |panic_info| {
+            orig_hook(panic_info);
+            process::exit(1);
+        }));
+    });
 }
@@ -1,22 +1,389 @@
-use std::time::Instant;
+//! Logging to STDERR, see [Logger].
+
+use std::{
+    fmt,
+    str::FromStr,
+    sync::{Arc, OnceLock},
+    time::Instant,
+};
+
+use anyhow::{anyhow, bail};
+use serde_json::json;
+
+use crate::{Envelope, NodeId};
+
+/// The importance of a log entry.
+#[derive(PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Debug)]
+pub enum Level {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl fmt::Display for Level {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.pad(match self {
+            Level::Error => "error",
+            Level::Warn => "warn",
+            Level::Info => "info",
+            Level::Debug => "debug",
+            Level::Trace => "trace",
+        })
+    }
+}
+
+impl FromStr for Level {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        Ok(match s.to_ascii_lowercase().as_str() {
+            "error" => Level::Error,
+            "warn" => Level::Warn,
+            "info" => Level::Info,
+            "debug" => Level::Debug,
+            "trace" => Level::Trace,
+            _ => bail!("invalid log level: {s}"),
+        })
+    }
+}
+
+/// Decides which entries are logged, see [Logger::from_env].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct LogFilter {
+    /// `None` turns logging off.
+    default: Option<Level>,
+    /// Sorted by decreasing target length, so the most specific comes first.
+    directives: Vec<(String, Option<Level>)>,
+}
+
+impl Default for LogFilter {
+    fn default() -> Self {
+        Self {
+            default: Some(Level::Debug),
+            directives: Vec::new(),
+        }
+    }
+}
+
+impl LogFilter {
+    /// Whether entries with `level` and `target` are logged.
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        let max_level = self
+            .directives
+            .iter()
+            .find(|(prefix, _)| is_below(target, prefix))
+            .map_or(self.default, |&(_, max_level)| max_level);
+        max_level.is_some_and(|max_level| level <= max_level)
+    }
+
+    /// The most verbose level enabled for any target.
+    fn max_level(&self) -> Option<Level> {
+        self.directives
+            .iter()
+            .map(|&(_, max_level)| max_level)
+            .chain([self.default])
+            .max()
+            .flatten()
+    }
+
+    /// Whether a directive applies to some message types only, i.e. has a
+    /// target below `msg`.
+    fn filters_message_types(&self) -> bool {
+        self.directives
+            .iter()
+            .any(|(target, _)| target.starts_with("msg::"))
+    }
+}
+
+impl FromStr for LogFilter {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> anyhow::Result<Self> {
+        let parse_level = |level: &str| match level.trim() {
+            "off" => Ok(None),
+            level => level.parse().map(Some),
+        };
+        let mut filter = LogFilter::default();
+        for directive in s.split(',').map(str::trim).filter(|d| !d.is_empty()) {
+            match directive.split_once('=') {
+                None => filter.default = parse_level(directive)?,
+                Some((target, level)) if !target.trim().is_empty() => filter
+                    .directives
+                    .push((target.trim().to_owned(), parse_level(level)?)),
+                Some(_) => return Err(anyhow!("missing target in {directive:?}")),
+            }
+        }
+        // Later directives for the same target win as the sort is stable.
+        filter.directives.reverse();
+        filter
+            .directives
+            .sort_by_key(|(target, _)| std::cmp::Reverse(target.len()));
+        Ok(filter)
+    }
+}
+
+/// Whether `target` is `prefix` or a target below it.
+fn is_below(target: &str, prefix: &str) -> bool {
+    target
+        .strip_prefix(prefix)
+        .is_some_and(|rest| rest.is_empty() || rest.starts_with("::"))
+}
+
+/// How log entries are written.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum LogFormat {
+    /// `[<seconds>] <level> <node id> <target>: <text>`
+    #[default]
+    Text,
+    /// One JSON object per line.
+    Json,
+}
 
 /// A logger that writes to STDERR and prepends relative timestamps.
+///
+/// Maelstrom keeps whatever a node writes to STDERR in its log file, so this
+/// is where [crate::run_node] logs every message it receives and sends. Once
+/// the node is initialized, its id is part of every entry.
+///
+/// [crate::run_node] also installs a [log] logger, so you can use the `log`
+/// macros (e.g. `log::info!`) in your node to log into the same sink. The
+/// target is your module's path, so you can filter by it. Libraries using
+/// `tracing` end up here as well if its `log` feature is enabled.
 #[derive(Debug)]
 pub struct Logger {
     start_time: Instant,
+    filter: LogFilter,
+    format: LogFormat,
+    /// `0` disables truncation.
+    max_len: usize,
+    node_id: OnceLock<NodeId>,
 }
 
 impl Default for Logger {
     fn default() -> Self {
+        Self::new(LogFilter::default(), LogFormat::default())
+    }
+}
+
+const DEFAULT_MAX_LEN: usize = 2000;
+
+impl Logger {
+    pub fn new(filter: LogFilter, format: LogFormat) -> Self {
         Self {
             start_time: Instant::now(),
+            filter,
+            format,
+            max_len: DEFAULT_MAX_LEN,
+            node_id: OnceLock::new(),
+        }
+    }
+
+    /// Creates a logger configured by environment variables:
+    ///
+    /// - `MAELSTROM_LOG` is a comma-separated list of directives. A directive
+    ///   is either a [Level] (or `off`) that applies to everything, or
+    ///   `target=level` for everything logged with that target or a target
+    ///   below it (`a::b` is below `a`). Messages are logged at
+    ///   [Level::Debug] with the target `msg`, or `msg::<type>` (where
+    ///   `<type>` is the `type` of their body) if a directive is below `msg`.
+    ///   The default is `debug`, i.e. everything but `trace`. Example:
+    ///   `info,msg=debug,msg::read_ok=off,broadcast=trace`.
+    /// - `MAELSTROM_LOG_FORMAT=json` writes JSON lines instead of plain text.
+    /// - `MAELSTROM_LOG_MAX_LEN` is the number of bytes after which entries
+    ///   are truncated (default: 2000, `0` disables truncation).
+    ///
+    /// Invalid values are reported and replaced by their default.
+    pub fn from_env() -> Self {
+        fn var<T>(name: &str, parse: impl FnOnce(&str) -> Option<T>) -> Option<T> {
+            let value = std::env::var(name).ok()?;
+            let parsed = parse(&value);
+            if parsed.is_none() {
+                eprintln!("ignoring invalid {name}: {value:?}");
+            }
+            parsed
+        }
+        let filter = var("MAELSTROM_LOG", |s| s.parse().ok()).unwrap_or_default();
+        let format = var("MAELSTROM_LOG_FORMAT", |s| match s {
+            "text" => Some(LogFormat::Text),
+            "json" => Some(LogFormat::Json),
+            _ => None,
+        })
+        .unwrap_or_default();
+        let mut logger = Self::new(filter, format);
+        logger.max_len =
+            var("MAELSTROM_LOG_MAX_LEN", |s| s.parse().ok()).unwrap_or(DEFAULT_MAX_LEN);
+        logger
+    }
+
+    /// Adds `node_id` to all following entries.
+    ///
+    /// Only the first call has an effect.
+    pub fn set_node_id(&self, node_id: NodeId) {
+        let _ = self.node_id.set(node_id);
+    }
+
+    /// Whether entries with `level` and `target` are logged.
+    pub fn enabled(&self, level: Level, target: &str) -> bool {
+        self.filter.enabled(level, target)
+    }
+
+    pub fn log(&self, level: Level, target: &str, text: &str) {
+        if self.enabled(level, target) {
+            eprintln!("{}", self.format(level, target, text));
+        }
+    }
+
+    /// Logs a message the node received (`direction` is `<`) or sent (`>`).
+    pub(crate) fn log_message(&self, direction: char, message: &str) {
+        // Only look at the message if it might be logged.
+        if self.filter.max_level() < Some(Level::Debug) {
+            return;
+        }
+        let text = format!("{direction} {message}");
+        // Finding the type means parsing the message, only do that if the
+        // filter depends on it.
+        if !self.filter.filters_message_types() {
+            self.log(Level::Debug, "msg", &text);
+            return;
+        }
+        let envelope = Envelope::parse(message).ok();
+        let message_type = envelope.as_ref().and_then(Envelope::message_type);
+        let target = format!("msg::{}", message_type.unwrap_or("unknown"));
+        self.log(Level::Debug, &target, &text);
+    }
+
+    fn format(&self, level: Level, target: &str, text: &str) -> String {
+        let elapsed = self.start_time.elapsed().as_millis() as f64 / 1000.0;
+        let text = truncate(text, self.max_len);
+        let node_id = self.node_id.get();
+        match self.format {
+            LogFormat::Text => {
+                let node_id = node_id.map_or(String::new(), |id| format!(" {id}"));
+                format!("[{elapsed:.1}] {level:5}{node_id} {target}: {text}")
+            }
+            LogFormat::Json => json!({
+                "time": elapsed,
+                "level": level.to_string(),
+                "node": node_id,
+                "target": target,
+                "text": text,
+            })
+            .to_string(),
         }
     }
 }
 
-impl Logger {
-    pub fn log(&self, message: &str) {
-        let elapsed = Instant::now() - self.start_time;
-        eprintln!("[{:.1}] {}", elapsed.as_millis() as f32 / 1000.0, message);
+/// Shortens `text` to at most `max_len` bytes (plus a note).
+fn truncate(text: &str, max_len: usize) -> std::borrow::Cow<'_, str> {
+    if max_len == 0 || text.len() <= max_len {
+        return text.into();
+    }
+    let end = (0..=max_len)
+        .rev()
+        .find(|&i| text.is_char_boundary(i))
+        .unwrap_or(0);
+    format!("{}... ({} bytes truncated)", &text[..end], text.len() - end).into()
+}
+
+/// Forwards records of the [log] facade to a [Logger].
+struct LogBridge(Arc<Logger>);
+
+impl log::Log for LogBridge {
+    fn enabled(&self, metadata: &log::Metadata) -> bool {
+        self.0
+            .enabled(from_log_level(metadata.level()), metadata.target())
+    }
+
+    fn log(&self, record: &log::Record) {
+        self.0.log(
+            from_log_level(record.level()),
+            record.target(),
+            &record.args().to_string(),
+        );
+    }
+
+    fn flush(&self) {}
+}
+
+fn from_log_level(level: log::Level) -> Level {
+    match level {
+        log::Level::Error => Level::Error,
+        log::Level::Warn => Level::Warn,
+        log::Level::Info => Level::Info,
+        log::Level::Debug => Level::Debug,
+        log::Level::Trace => Level::Trace,
+    }
+}
+
+/// Makes `logger` the target of the [log] macros, unless another logger was
+/// installed before.
+pub(crate) fn install_log_bridge(logger: Arc<Logger>) {
+    let max_level = match logger.filter.max_level() {
+        None => log::LevelFilter::Off,
+        Some(Level::Error) => log::LevelFilter::Error,
+        Some(Level::Warn) => log::LevelFilter::Warn,
+        Some(Level::Info) => log::LevelFilter::Info,
+        Some(Level::Debug) => log::LevelFilter::Debug,
+        Some(Level::Trace) => log::LevelFilter::Trace,
+    };
+    if log::set_boxed_logger(Box::new(LogBridge(logger))).is_ok() {
+        log::set_max_level(max_level);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn filter_uses_most_specific_directive() {
+        let filter: LogFilter = "info, msg=debug, msg::read_ok=off, broadcast::gossip=trace"
+            .parse()
+            .unwrap();
+        assert!(filter.enabled(Level::Info, "g_counter"));
+        assert!(!filter.enabled(Level::Debug, "g_counter"));
+        assert!(filter.enabled(Level::Debug, "msg::read"));
+        assert!(!filter.enabled(Level::Error, "msg::read_ok"));
+        assert!(filter.enabled(Level::Trace, "broadcast::gossip"));
+        assert!(!filter.enabled(Level::Trace, "broadcast::gossiping"));
+        assert_eq!(filter.max_level(), Some(Level::Trace));
+        assert!(filter.filters_message_types());
+        assert!(!"info,msg=debug"
+            .parse::<LogFilter>()
+            .unwrap()
+            .filters_message_types());
+    }
+
+    #[test]
+    fn filter_rejects_invalid_directives() {
+        assert!("loud".parse::<LogFilter>().is_err());
+        assert!("=info".parse::<LogFilter>().is_err());
+        assert_eq!("off".parse::<LogFilter>().unwrap().max_level(), None);
+    }
+
+    #[test]
+    fn levels_are_padded() {
+        assert_eq!(format!("[{:5}]", Level::Warn), "[warn ]");
+        assert_eq!(format!("[{:5}]", Level::Error), "[error]");
+    }
+
+    #[test]
+    fn truncate_respects_char_boundaries() {
+        assert_eq!(truncate("short", 10), "short");
+        assert_eq!(truncate("short", 0), "short");
+        assert_eq!(truncate("äöü", 3), "ä... (4 bytes truncated)");
+    }
+
+    #[test]
+    fn json_entries_include_node_id() {
+        let logger = Logger::new(LogFilter::default(), LogFormat::Json);
+        logger.set_node_id("n1".parse().unwrap());
+        let entry: serde_json::Value =
+            serde_json::from_str(&logger.format(Level::Warn, "test", "hello")).unwrap();
+        assert_eq!(entry["node"], "n1");
+        assert_eq!(entry["level"], "warn");
+        assert_eq!(entry["text"], "hello");
     }
 }
@@ -1,9 +1,19 @@
-use std::{marker::PhantomData, ops::RangeFrom, sync::mpsc};
+use std::{
+    fmt,
+    marker::PhantomData,
+    ops::RangeFrom,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
 
 use anyhow::Result;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
 
-use crate::NodeId;
+use crate::{
+    output::OutputSender,
+    rpc::{Completion, PendingRpcs},
+    Envelope, NodeId, RpcResult, Timers,
+};
 
 /// A message following Maelstrom's protocol.
 ///
@@ -13,13 +23,9 @@ use crate::NodeId;
 /// practical reasons. Most of our code wants to match on `payload` and does
 /// not care about those fields. On the other hand, replying to messages does
 /// not need the payload, so this seems to be a more natural structure.
-#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
-#[serde(bound(
-    serialize = "P: Clone + Serialize",
-    deserialize = "P: Deserialize<'de>"
-))]
+#[derive(PartialEq, Eq, Clone, Debug, Deserialize)]
+#[serde(bound(deserialize = "P: Deserialize<'de>"))]
 #[serde(from = "MaelstromMessage<P>")]
-#[serde(into = "MaelstromMessage<P>")]
 pub struct Message<P> {
     pub header: MessageHeader,
     pub payload: P,
@@ -34,6 +40,38 @@ pub struct MessageHeader {
     pub in_reply_to: Option<MessageId>,
 }
 
+impl MessageHeader {
+    /// Returns the header as a [RequestHeader] if the message can be replied
+    /// to, i.e. if it has a `msg_id`.
+    ///
+    /// The error is a [ErrorCode::MalformedRequest], so handlers can simply
+    /// use `?`.
+    pub fn request(&self) -> Result<RequestHeader, ErrorPayload> {
+        match self.msg_id {
+            Some(msg_id) => Ok(RequestHeader {
+                src: self.src,
+                dest: self.dest,
+                msg_id,
+            }),
+            None => Err(ErrorPayload {
+                code: ErrorCode::MalformedRequest,
+                text: "expected a request with a msg_id".to_owned(),
+            }),
+        }
+    }
+}
+
+/// The header of a message that can be replied to.
+///
+/// Messages without a `msg_id` (see [MessageTransmitter::notify]) don't have
+/// one, so [MessageTransmitter::reply] can't be used for them.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct RequestHeader {
+    pub src: NodeId,
+    pub dest: NodeId,
+    pub msg_id: MessageId,
+}
+
 impl<P> Message<P> {
     pub fn mapped<Q: From<P>>(self) -> Message<Q> {
         Message {
@@ -47,9 +85,15 @@ impl<P> Message<P> {
 ///
 /// This identifier is automatically created within [MessageTransmitter],
 /// ensuring it gets incremented for each message.
-#[derive(PartialEq, Eq, Clone, Copy, Debug, Serialize, Deserialize)]
+#[derive(PartialOrd, Ord, PartialEq, Eq, Clone, Copy, Hash, Debug, Serialize, Deserialize)]
 pub struct MessageId(u64);
 
+impl From<u64> for MessageId {
+    fn from(source: u64) -> Self {
+        Self(source)
+    }
+}
+
 #[derive(Debug)]
 struct MessageIdGenerator {
     iter: RangeFrom<u64>,
@@ -73,11 +117,10 @@ impl MessageIdGenerator {
 /// The only way of sending [Message]s.
 ///
 /// Note that all messages created with [MessageTransmitter] will contain a
-/// unique [MessageId]. This is a simplification, Maelstrom actually supports
-/// messages without an ID.
+/// unique [MessageId], except for those sent with [MessageTransmitter::notify].
 ///
 /// It is the only way of sending messages because:
-/// - [crate::run_node] will start a thread that keeps a lock on STDOUT.
+/// - [crate::run_node] will start a thread that writes to STDOUT.
 /// - This thread reads messages from a channel and outputs them.
 /// - The [MessageTransmitter] created in [crate::run_node] contains the
 ///   *only* sender for this channel.
@@ -85,16 +128,25 @@ impl MessageIdGenerator {
 pub struct MessageTransmitter<P> {
     src: NodeId,
     msg_ids: MessageIdGenerator,
-    tx: mpsc::SyncSender<String>,
+    tx: OutputSender,
+    rpcs: Arc<Mutex<PendingRpcs>>,
+    timers: Timers,
     _payload: PhantomData<P>,
 }
 
-impl<P: Clone + Serialize> MessageTransmitter<P> {
-    pub(crate) fn new(src: NodeId, tx: mpsc::SyncSender<String>) -> Self {
+impl<P: Serialize> MessageTransmitter<P> {
+    pub(crate) fn new(
+        src: NodeId,
+        tx: OutputSender,
+        rpcs: Arc<Mutex<PendingRpcs>>,
+        timers: Timers,
+    ) -> Self {
         Self {
             src,
             msg_ids: MessageIdGenerator::default(),
             tx,
+            rpcs,
+            timers,
             _payload: PhantomData,
         }
     }
@@ -105,10 +157,20 @@ impl<P: Clone + Serialize> MessageTransmitter<P> {
             src: self.src,
             msg_ids: self.msg_ids,
             tx: self.tx,
+            rpcs: self.rpcs,
+            timers: self.timers,
             _payload: PhantomData,
         }
     }
 
+    /// Returns the node's [Timers].
+    ///
+    /// Timers live alongside the transmitter because both are handed to the
+    /// node once after initialization.
+    pub fn timers(&self) -> &Timers {
+        &self.timers
+    }
+
     /// Prepares a message for later sending.
     pub fn prepare<Q>(
         &mut self,
@@ -129,9 +191,29 @@ impl<P: Clone + Serialize> MessageTransmitter<P> {
 
     /// Sends a prepared message.
     pub fn send_message(&mut self, message: &Message<P>) {
-        self.tx
-            .send(serialize_message(message))
-            .expect("sending message should succeed");
+        self.tx.send(message);
+    }
+
+    /// Sends a message without [MessageId] to `dest`.
+    ///
+    /// Use this for messages that don't need a reply (e.g. gossip). Receivers
+    /// can't reply to it and know they don't have to.
+    pub fn notify(&mut self, dest: NodeId, payload: P) {
+        self.notify_any(dest, payload);
+    }
+
+    /// Like [MessageTransmitter::notify], but with any payload type.
+    pub(crate) fn notify_any<Q: Serialize>(&mut self, dest: NodeId, payload: Q) {
+        let message = Message {
+            header: MessageHeader {
+                src: self.src,
+                dest,
+                msg_id: None,
+                in_reply_to: None,
+            },
+            payload,
+        };
+        self.tx.send(&message);
     }
 
     /// Sends a message to `dest` specified by `payload`.
@@ -143,26 +225,291 @@ impl<P: Clone + Serialize> MessageTransmitter<P> {
         message.header.msg_id.expect("msg_id should be set")
     }
 
+    /// Sends a request to `dest` and waits for its reply.
+    ///
+    /// The reply will be passed to [crate::NodeState::rpc_reply] instead of
+    /// [crate::NodeState::handle]. If there is no reply within `timeout`,
+    /// [crate::NodeState::rpc_reply] receives an [crate::ErrorCode::Timeout]
+    /// error instead. Replies arriving after that are passed to
+    /// [crate::NodeState::handle] as usual.
+    pub fn call(&mut self, dest: NodeId, payload: P, timeout: Option<Duration>) -> MessageId {
+        let message = self.prepare(dest, None, payload);
+        self.call_message(&message, timeout)
+    }
+
+    /// Like [MessageTransmitter::send] (or [MessageTransmitter::reply] if
+    /// `in_reply_to` is set), but with any payload type.
+    pub(crate) fn send_any<Q: Serialize>(
+        &mut self,
+        dest: NodeId,
+        in_reply_to: Option<MessageId>,
+        payload: Q,
+    ) -> MessageId {
+        let message = self.prepare(dest, in_reply_to, payload);
+        self.tx.send(&message);
+        message.header.msg_id.expect("msg_id should be set")
+    }
+
+    /// Like [MessageTransmitter::call], but with any payload type.
+    pub(crate) fn call_any<Q: Serialize>(
+        &mut self,
+        dest: NodeId,
+        payload: Q,
+        timeout: Option<Duration>,
+    ) -> MessageId {
+        let message = self.prepare(dest, None, payload);
+        let msg_id = message.header.msg_id.expect("msg_id should be set");
+        self.register_rpc(msg_id, timeout, Completion::Node);
+        self.tx.send(&message);
+        msg_id
+    }
+
+    /// Like [MessageTransmitter::call], but passes the outcome to `on_reply`.
+    pub fn call_with(
+        &mut self,
+        dest: NodeId,
+        payload: P,
+        timeout: Option<Duration>,
+        on_reply: impl FnOnce(RpcResult) + Send + 'static,
+    ) -> MessageId {
+        let message = self.prepare(dest, None, payload);
+        let msg_id = message.header.msg_id.expect("msg_id should be set");
+        self.register_rpc(msg_id, timeout, Completion::Callback(Box::new(on_reply)));
+        self.send_message(&message);
+        msg_id
+    }
+
+    /// Sends a prepared message as a request, like [MessageTransmitter::call].
+    ///
+    /// Re-sending the same message with [MessageTransmitter::send_message]
+    /// (e.g. for retries) does not register another RPC, the first reply
+    /// completes it.
+    pub fn call_message(&mut self, message: &Message<P>, timeout: Option<Duration>) -> MessageId {
+        let msg_id = message.header.msg_id.expect("msg_id should be set");
+        self.register_rpc(msg_id, timeout, Completion::Node);
+        self.send_message(message);
+        msg_id
+    }
+
+    fn register_rpc(
+        &mut self,
+        msg_id: MessageId,
+        timeout: Option<Duration>,
+        completion: Completion,
+    ) {
+        self.rpcs
+            .lock()
+            .expect("lock should not be poisoned")
+            .insert(msg_id, timeout, completion);
+    }
+
     /// Sends a reply for another message.
     ///
     /// The message will be assigned a unique [MessageId] and will use the
     /// original message's ID for `in_reply_to`.
-    pub fn reply(&mut self, header: &MessageHeader, payload: P) -> MessageId {
-        assert!(header.msg_id.is_some());
-        let message = self.prepare(header.src, header.msg_id, payload);
+    pub fn reply(&mut self, request: &RequestHeader, payload: P) -> MessageId {
+        let message = self.prepare(request.src, Some(request.msg_id), payload);
         self.send_message(&message);
         message.header.msg_id.expect("msg_id should be set")
     }
+
+    /// Sends an error reply for another message.
+    ///
+    /// Like [MessageTransmitter::reply], but with an [ErrorPayload] instead of
+    /// the transmitter's payload type.
+    pub fn reply_error(
+        &mut self,
+        request: &RequestHeader,
+        code: ErrorCode,
+        text: impl Into<String>,
+    ) -> MessageId {
+        let payload = ErrorResponsePayload::Error(ErrorPayload {
+            code,
+            text: text.into(),
+        });
+        let message = self.prepare(request.src, Some(request.msg_id), payload);
+        self.tx.send(&message);
+        message.header.msg_id.expect("msg_id should be set")
+    }
+}
+
+/// The payload of Maelstrom's `error` message.
+///
+/// Use it as a newtype variant named `error` in your payload enums to receive
+/// errors, e.g. `#[serde(rename = "error")] Error(ErrorPayload)`.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct ErrorPayload {
+    pub code: ErrorCode,
+    #[serde(default)]
+    pub text: String,
+}
+
+impl fmt::Display for ErrorPayload {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.text, self.code)
+    }
+}
+
+impl std::error::Error for ErrorPayload {}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ErrorResponsePayload {
+    Error(ErrorPayload),
+}
+
+/// An error reply to `request`.
+///
+/// Unlike [MessageTransmitter::reply_error], the reply has no `msg_id`.
+pub(crate) fn error_reply(request: &RequestHeader, error: ErrorPayload) -> Message<impl Serialize> {
+    reply_without_id(request, ErrorResponsePayload::Error(error))
+}
+
+/// A reply without `msg_id` to `request`.
+pub(crate) fn reply_without_id<P: Serialize>(request: &RequestHeader, payload: P) -> Message<P> {
+    Message {
+        header: MessageHeader {
+            src: request.dest,
+            dest: request.src,
+            msg_id: None,
+            in_reply_to: Some(request.msg_id),
+        },
+        payload,
+    }
+}
+
+/// Maelstrom's standard error codes.
+///
+/// See <https://github.com/jepsen-io/maelstrom/blob/main/doc/protocol.md#errors>.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Hash, Serialize, Deserialize)]
+#[serde(from = "u32", into = "u32")]
+pub enum ErrorCode {
+    /// The requested operation could not be completed within a timeout.
+    Timeout,
+    /// The requested node does not exist.
+    NodeNotFound,
+    /// The requested operation is not supported by the current implementation.
+    NotSupported,
+    /// The operation definitely cannot be performed at this time.
+    TemporarilyUnavailable,
+    /// The client's request did not conform to the server's expectations.
+    MalformedRequest,
+    /// Some kind of general, indefinite error occurred.
+    Crash,
+    /// Some kind of general, definite error occurred.
+    Abort,
+    /// The client requested an operation on a key which does not exist.
+    KeyDoesNotExist,
+    /// The client tried to create a key which already exists.
+    KeyAlreadyExists,
+    /// A precondition (e.g. a compare-and-set comparison) failed.
+    PreconditionFailed,
+    /// The requested transaction has been aborted because of a conflict.
+    TxnConflict,
+    /// Any other (e.g. custom) error code.
+    Other(u32),
+}
+
+impl ErrorCode {
+    /// Whether the error is *definite*.
+    ///
+    /// A definite error means the requested operation definitely did not
+    /// happen. Indefinite errors (and unknown codes) may or may not have taken
+    /// effect.
+    pub fn is_definite(self) -> bool {
+        use ErrorCode::*;
+        match self {
+            Timeout | Crash | Other(_) => false,
+            NodeNotFound
+            | NotSupported
+            | TemporarilyUnavailable
+            | MalformedRequest
+            | Abort
+            | KeyDoesNotExist
+            | KeyAlreadyExists
+            | PreconditionFailed
+            | TxnConflict => true,
+        }
+    }
+}
+
+impl From<u32> for ErrorCode {
+    fn from(source: u32) -> Self {
+        use ErrorCode::*;
+        match source {
+            0 => Timeout,
+            1 => NodeNotFound,
+            10 => NotSupported,
+            11 => TemporarilyUnavailable,
+            12 => MalformedRequest,
+            13 => Crash,
+            14 => Abort,
+            20 => KeyDoesNotExist,
+            21 => KeyAlreadyExists,
+            22 => PreconditionFailed,
+            30 => TxnConflict,
+            other => Other(other),
+        }
+    }
+}
+
+impl From<ErrorCode> for u32 {
+    fn from(source: ErrorCode) -> Self {
+        use ErrorCode::*;
+        match source {
+            Timeout => 0,
+            NodeNotFound => 1,
+            NotSupported => 10,
+            TemporarilyUnavailable => 11,
+            MalformedRequest => 12,
+            Crash => 13,
+            Abort => 14,
+            KeyDoesNotExist => 20,
+            KeyAlreadyExists => 21,
+            PreconditionFailed => 22,
+            TxnConflict => 30,
+            Other(code) => code,
+        }
+    }
+}
+
+impl fmt::Display for ErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use ErrorCode::*;
+        let name = match self {
+            Timeout => "timeout",
+            NodeNotFound => "node-not-found",
+            NotSupported => "not-supported",
+            TemporarilyUnavailable => "temporarily-unavailable",
+            MalformedRequest => "malformed-request",
+            Crash => "crash",
+            Abort => "abort",
+            KeyDoesNotExist => "key-does-not-exist",
+            KeyAlreadyExists => "key-already-exists",
+            PreconditionFailed => "precondition-failed",
+            TxnConflict => "txn-conflict",
+            Other(code) => return write!(f, "error code {code}"),
+        };
+        f.write_str(name)
+    }
 }
 
 /// Serializes a message to a JSON string.
-pub fn serialize_message<P: Clone + Serialize>(message: &Message<P>) -> String {
+pub fn serialize_message<P: Serialize>(message: &Message<P>) -> String {
     serde_json::to_string(message).expect("message should be serializable")
 }
 
 /// Deserializes a message from a JSON string.
-pub fn deserialize_message<P: for<'a> Deserialize<'a>>(message: &str) -> Result<Message<P>> {
-    serde_json::from_str(message).map_err(Into::into)
+///
+/// The payload may borrow from `message`. Use [Envelope] to look at the header
+/// before deciding how to deserialize the payload.
+pub fn deserialize_message<'a, P: Deserialize<'a>>(message: &'a str) -> Result<Message<P>> {
+    Envelope::parse(message)?.message()
+}
+
+/// Deserializes only a message's header from a JSON string.
+pub fn deserialize_header(message: &str) -> Result<MessageHeader> {
+    Envelope::parse(message).map(|envelope| envelope.header)
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -174,7 +521,9 @@ struct MaelstromMessage<P> {
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 struct MaelstromMessageBody<P> {
+    #[serde(skip_serializing_if = "Option::is_none")]
     msg_id: Option<MessageId>,
+    #[serde(skip_serializing_if = "Option::is_none")]
     in_reply_to: Option<MessageId>,
     #[serde(flatten)]
     payload: P,
@@ -194,16 +543,74 @@ impl<P> From<MaelstromMessage<P>> for Message<P> {
     }
 }
 
-impl<P> From<Message<P>> for MaelstromMessage<P> {
-    fn from(source: Message<P>) -> Self {
-        Self {
-            src: source.header.src,
-            dest: source.header.dest,
+// Serializes a view that borrows the payload, so sending doesn't need to
+// clone it.
+impl<P: Serialize> Serialize for Message<P> {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        MaelstromMessage {
+            src: self.header.src,
+            dest: self.header.dest,
             body: MaelstromMessageBody {
-                msg_id: source.header.msg_id,
-                in_reply_to: source.header.in_reply_to,
-                payload: source.payload,
+                msg_id: self.header.msg_id,
+                in_reply_to: self.header.in_reply_to,
+                payload: &self.payload,
             },
         }
+        .serialize(serializer)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn serializes_payloads_that_are_not_clone() {
+        #[derive(Serialize)]
+        #[serde(tag = "type", rename_all = "snake_case")]
+        enum Payload {
+            ReadOk { value: Box<[u64]> },
+        }
+
+        let message = Message {
+            header: MessageHeader {
+                src: "n1".parse().unwrap(),
+                dest: "c1".parse().unwrap(),
+                msg_id: Some(MessageId(2)),
+                in_reply_to: Some(MessageId(1)),
+            },
+            payload: Payload::ReadOk {
+                value: Box::new([1, 2]),
+            },
+        };
+        let serialized: serde_json::Value =
+            serde_json::from_str(&serialize_message(&message)).unwrap();
+        assert_eq!(
+            serialized,
+            json!({
+                "src": "n1",
+                "dest": "c1",
+                "body": {"type": "read_ok", "msg_id": 2, "in_reply_to": 1, "value": [1, 2]},
+            })
+        );
+    }
+
+    #[test]
+    fn notifications_omit_message_ids() {
+        let message = Message {
+            header: MessageHeader {
+                src: "n1".parse().unwrap(),
+                dest: "n2".parse().unwrap(),
+                msg_id: None,
+                in_reply_to: None,
+            },
+            payload: json!({"type": "tick"}),
+        };
+        assert_eq!(
+            serialize_message(&message),
+            r#"{"src":"n1","dest":"n2","body":{"type":"tick"}}"#
+        );
     }
 }
@@ -0,0 +1,191 @@
+use std::{collections::BTreeMap, fmt, time::Duration};
+
+use serde::{Deserialize, Serialize};
+
+use crate::NodeId;
+
+/// Traffic and timing statistics of a node.
+///
+/// [crate::run_node] logs them when the node shuts down and answers `metrics`
+/// requests with them if [crate::NodeOptions::answer_metrics_requests] is set.
+/// Inside a [crate::sim::Simulation], use [crate::sim::Simulation::metrics].
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct NodeMetrics {
+    pub received: MessageCounts,
+    pub sent: MessageCounts,
+    /// The number of messages sent to each destination.
+    pub sent_by_dest: BTreeMap<NodeId, u64>,
+    /// How long the node took to handle its inputs.
+    pub handler_latency: Histogram,
+    /// How long RPCs took until their reply arrived.
+    pub rpc_round_trip: Histogram,
+    /// The number of RPCs that timed out.
+    pub rpc_timeouts: u64,
+}
+
+impl NodeMetrics {
+    pub(crate) fn record_received(&mut self, message: &str, message_type: Option<&str>) {
+        self.received.record(message.len(), message_type);
+    }
+
+    pub(crate) fn record_sent(&mut self, bytes: usize, dest: NodeId, message_type: Option<&str>) {
+        self.sent.record(bytes, message_type);
+        *self.sent_by_dest.entry(dest).or_default() += 1;
+    }
+}
+
+impl fmt::Display for NodeMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        writeln!(f, "received: {}", self.received)?;
+        writeln!(f, "sent: {}", self.sent)?;
+        let by_dest = self
+            .sent_by_dest
+            .iter()
+            .map(|(dest, n)| format!("{dest}: {n}"));
+        writeln!(f, "sent by destination: {}", join(by_dest))?;
+        writeln!(f, "handler latency: {}", self.handler_latency)?;
+        write!(
+            f,
+            "rpc round trip: {} ({} timed out)",
+            self.rpc_round_trip, self.rpc_timeouts
+        )
+    }
+}
+
+/// Counts messages and their size.
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct MessageCounts {
+    pub messages: u64,
+    pub bytes: u64,
+    /// The number of messages for each `type` of body.
+    pub by_type: BTreeMap<String, u64>,
+}
+
+impl MessageCounts {
+    fn record(&mut self, bytes: usize, message_type: Option<&str>) {
+        self.messages += 1;
+        self.bytes += bytes as u64;
+        let message_type = message_type.unwrap_or("unknown");
+        // Avoid allocating the key for every message.
+        match self.by_type.get_mut(message_type) {
+            Some(count) => *count += 1,
+            None => {
+                self.by_type.insert(message_type.to_owned(), 1);
+            }
+        }
+    }
+}
+
+impl fmt::Display for MessageCounts {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let by_type = self.by_type.iter().map(|(t, n)| format!("{t}: {n}"));
+        write!(
+            f,
+            "{} messages, {} bytes ({})",
+            self.messages,
+            self.bytes,
+            join(by_type)
+        )
+    }
+}
+
+fn join(items: impl Iterator<Item = String>) -> String {
+    items.collect::<Vec<_>>().join(", ")
+}
+
+/// A histogram of durations with power-of-two buckets.
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+pub struct Histogram {
+    pub count: u64,
+    pub sum_us: u64,
+    pub max_us: u64,
+    /// `buckets[i]` counts durations of less than `2^i` microseconds (and at
+    /// least `2^(i-1)`).
+    pub buckets: Vec<u64>,
+}
+
+impl Histogram {
+    pub fn record(&mut self, duration: Duration) {
+        let us = duration.as_micros().try_into().unwrap_or(u64::MAX);
+        self.count += 1;
+        self.sum_us = self.sum_us.saturating_add(us);
+        self.max_us = self.max_us.max(us);
+        let bucket = (u64::BITS - us.leading_zeros()) as usize;
+        if self.buckets.len() <= bucket {
+            self.buckets.resize(bucket + 1, 0);
+        }
+        self.buckets[bucket] += 1;
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_micros(self.sum_us / self.count))
+    }
+
+    pub fn max(&self) -> Option<Duration> {
+        (self.count > 0).then(|| Duration::from_micros(self.max_us))
+    }
+
+    /// An upper bound of the `q`-quantile (e.g. `0.5` for the median).
+    ///
+    /// This is precise up to a factor of two because of the bucket sizes.
+    pub fn quantile(&self, q: f64) -> Option<Duration> {
+        let rank = (q.clamp(0.0, 1.0) * self.count as f64).ceil().max(1.0) as u64;
+        let mut seen = 0;
+        for (i, &n) in self.buckets.iter().enumerate() {
+            seen += n;
+            if seen >= rank {
+                let upper_bound = 1u64.checked_shl(i as u32).unwrap_or(u64::MAX);
+                return Some(Duration::from_micros(upper_bound.min(self.max_us)));
+            }
+        }
+        None
+    }
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (
+            self.mean(),
+            self.quantile(0.5),
+            self.quantile(0.99),
+            self.max(),
+        ) {
+            (Some(mean), Some(p50), Some(p99), Some(max)) => write!(
+                f,
+                "n={} mean={mean:?} p50<={p50:?} p99<={p99:?} max={max:?}",
+                self.count
+            ),
+            _ => write!(f, "n=0"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn histogram_quantiles_are_upper_bounds() {
+        let mut histogram = Histogram::default();
+        for us in [1, 3, 5, 100, 1000] {
+            histogram.record(Duration::from_micros(us));
+        }
+        assert_eq!(histogram.mean(), Some(Duration::from_micros(221)));
+        assert_eq!(histogram.quantile(0.5), Some(Duration::from_micros(8)));
+        assert_eq!(histogram.quantile(1.0), Some(Duration::from_micros(1000)));
+        assert_eq!(Histogram::default().quantile(0.5), None);
+    }
+
+    #[test]
+    fn counts_sent_messages() {
+        let (n2, c1) = ("n2".parse().unwrap(), "c1".parse().unwrap());
+        let mut metrics = NodeMetrics::default();
+        metrics.record_sent(10, n2, Some("gossip"));
+        metrics.record_sent(20, c1, Some("read_ok"));
+        metrics.record_sent(10, n2, Some("gossip"));
+        assert_eq!(metrics.sent.messages, 3);
+        assert_eq!(metrics.sent.bytes, 40);
+        assert_eq!(metrics.sent.by_type["gossip"], 2);
+        assert_eq!(metrics.sent_by_dest[&n2], 2);
+    }
+}
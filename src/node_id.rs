@@ -1,72 +1,204 @@
-use std::{fmt, str::FromStr};
+use std::{cmp::Ordering, collections::BTreeSet, fmt, str::FromStr, sync::Mutex};
 
 use derive_more::derive::Display;
 use serde_with::{DeserializeFromStr, SerializeDisplay};
 
-const NODE_ID_LENGTH: usize = 8;
+/// Ids up to this length are stored inline. This fills the space an interned
+/// `&str` takes anyway.
+const INLINE_LENGTH: usize = 22;
 
 /// A node's unique identifier within the network.
-// We want `NodeId` to be `Copy`. I opted for storing 8 ASCII bytes, but this
-// is an implementation detail. Because this is a private field, changing this
-// will not affect other parts of the code.
-#[derive(
-    PartialOrd, Ord, PartialEq, Eq, Clone, Copy, Hash, SerializeDisplay, DeserializeFromStr,
-)]
-pub struct NodeId([u8; NODE_ID_LENGTH]);
+///
+/// Use [node_id!] for ids known at compile time.
+// We want `NodeId` to be `Copy`. Short ids (which is almost all of them) are
+// stored inline, longer ones are interned, i.e. leaked once per distinct id.
+// Both are implementation details. Because this is a private field, changing
+// this will not affect other parts of the code.
+#[derive(PartialEq, Eq, Clone, Copy, Hash, SerializeDisplay, DeserializeFromStr)]
+pub struct NodeId(Repr);
+
+// Equal ids always have the same representation, so comparing and hashing
+// `Repr` is the same as comparing and hashing the ids.
+#[derive(PartialEq, Eq, Clone, Copy, Hash)]
+enum Repr {
+    /// The first `len` bytes are the id, the rest are zeros.
+    Inline { len: u8, bytes: [u8; INLINE_LENGTH] },
+    /// Always longer than [INLINE_LENGTH].
+    Interned(&'static str),
+}
 
 #[derive(PartialEq, Eq, Clone, Copy, Debug, Display)]
 pub enum ParseNodeIdError {
-    #[display("node id is longer than 8 characters")]
-    TooLong,
+    #[display("node id is empty")]
+    Empty,
     #[display("node id contains invalid byte: {_0:x}")]
     InvalidByte(u8),
 }
 
+/// What kind of participant a [NodeId] refers to, following Maelstrom's
+/// naming conventions.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum NodeKind {
+    /// `c` followed by digits, e.g. `c1`.
+    Client,
+    /// `n` followed by digits, e.g. `n1`.
+    Server,
+    /// Anything else, e.g. `seq-kv`.
+    Service,
+}
+
+/// Creates a [NodeId] at compile time.
+///
+/// Invalid ids fail to compile.
+///
+/// ```
+/// # use fly_into_the_maelstrom::*;
+/// const COORDINATOR: NodeId = node_id!("n0");
+/// assert_eq!(COORDINATOR.kind(), NodeKind::Server);
+/// ```
+#[macro_export]
+macro_rules! node_id {
+    ($id:expr) => {{
+        const ID: $crate::NodeId = $crate::NodeId::from_static($id);
+        ID
+    }};
+}
+
+/// The node id of Maelstrom's sequentially consistent key-value store.
+pub const SEQ_KV: NodeId = node_id!("seq-kv");
+
+/// The node id of Maelstrom's linearizable key-value store.
+pub const LIN_KV: NodeId = node_id!("lin-kv");
+
+/// The node id of Maelstrom's last-write-wins key-value store.
+pub const LWW_KV: NodeId = node_id!("lww-kv");
+
+/// The node id of Maelstrom's linearizable timestamp oracle.
+pub const LIN_TSO: NodeId = node_id!("lin-tso");
+
+static INTERNED: Mutex<BTreeSet<&'static str>> = Mutex::new(BTreeSet::new());
+
 impl NodeId {
-    /// Parse a string `s` to return a [NodeId].
+    /// Creates a [NodeId] from a string that lives forever, panicking if it is
+    /// invalid.
     ///
-    /// This is a const version of [FromStr::from_str].
-    pub const fn from_str(s: &str) -> Result<Self, ParseNodeIdError> {
-        let bytes = s.as_bytes();
-        if bytes.len() > NODE_ID_LENGTH {
-            return Err(ParseNodeIdError::TooLong);
+    /// This is a const version of [FromStr::from_str], see [node_id!].
+    pub const fn from_static(s: &'static str) -> Self {
+        if let Err(err) = validate(s) {
+            match err {
+                ParseNodeIdError::Empty => panic!("node id is empty"),
+                ParseNodeIdError::InvalidByte(_) => panic!("node id contains invalid byte"),
+            }
         }
+        if s.len() > INLINE_LENGTH {
+            return Self(Repr::Interned(s));
+        }
+        Self(inline(s))
+    }
 
-        let mut result = [0; NODE_ID_LENGTH];
-        let mut idx = 0;
-        while idx < bytes.len() {
-            let b = bytes[idx];
-            if b.is_ascii_alphabetic() || b.is_ascii_digit() || b.is_ascii_punctuation() {
-                result[idx] = b;
-            } else {
-                return Err(ParseNodeIdError::InvalidByte(b));
-            }
-            idx += 1;
+    /// The id as a string.
+    pub fn as_str(&self) -> &str {
+        std::str::from_utf8(self.as_bytes()).expect("node ids should be ASCII")
+    }
+
+    fn as_bytes(&self) -> &[u8] {
+        match &self.0 {
+            Repr::Inline { len, bytes } => &bytes[..*len as usize],
+            Repr::Interned(s) => s.as_bytes(),
+        }
+    }
+
+    /// Classifies the id, see [NodeKind].
+    pub fn kind(&self) -> NodeKind {
+        let s = self.as_str();
+        let numbered = |prefix| {
+            s.strip_prefix(prefix)
+                .is_some_and(|n| !n.is_empty() && n.bytes().all(|b| b.is_ascii_digit()))
+        };
+        if numbered('c') {
+            NodeKind::Client
+        } else if numbered('n') {
+            NodeKind::Server
+        } else {
+            NodeKind::Service
+        }
+    }
+}
+
+const fn validate(s: &str) -> Result<(), ParseNodeIdError> {
+    let bytes = s.as_bytes();
+    if bytes.is_empty() {
+        return Err(ParseNodeIdError::Empty);
+    }
+    let mut idx = 0;
+    while idx < bytes.len() {
+        let b = bytes[idx];
+        if !(b.is_ascii_alphabetic() || b.is_ascii_digit() || b.is_ascii_punctuation()) {
+            return Err(ParseNodeIdError::InvalidByte(b));
         }
+        idx += 1;
+    }
+    Ok(())
+}
+
+/// Copies a short, valid id into the inline representation.
+const fn inline(s: &str) -> Repr {
+    let bytes = s.as_bytes();
+    let mut result = [0; INLINE_LENGTH];
+    let mut idx = 0;
+    while idx < bytes.len() {
+        result[idx] = bytes[idx];
+        idx += 1;
+    }
+    Repr::Inline {
+        len: bytes.len() as u8,
+        bytes: result,
+    }
+}
+
+/// Returns a `'static` copy of `s`, allocating it only once.
+fn intern(s: &str) -> &'static str {
+    let mut interned = INTERNED.lock().expect("lock should not be poisoned");
+    if let Some(&existing) = interned.get(s) {
+        return existing;
+    }
+    let leaked: &'static str = Box::leak(s.into());
+    interned.insert(leaked);
+    leaked
+}
+
+impl PartialOrd for NodeId {
+    fn partial_cmp(&self, other: &Self) -> Option<Ordering> {
+        Some(self.cmp(other))
+    }
+}
 
-        Ok(Self(result))
+impl Ord for NodeId {
+    fn cmp(&self, other: &Self) -> Ordering {
+        self.as_bytes().cmp(other.as_bytes())
     }
 }
 
 impl fmt::Debug for NodeId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        write!(f, "NodeId(\"{}\")", self)
+        write!(f, "NodeId({:?})", self.as_str())
     }
 }
 
 impl fmt::Display for NodeId {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
-        for c in self.0.iter().copied().take_while(|&c| c != 0) {
-            write!(f, "{}", char::from(c))?;
-        }
-        Ok(())
+        f.write_str(self.as_str())
     }
 }
 
 impl FromStr for NodeId {
     type Err = ParseNodeIdError;
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        NodeId::from_str(s)
+        validate(s)?;
+        if s.len() > INLINE_LENGTH {
+            return Ok(Self(Repr::Interned(intern(s))));
+        }
+        Ok(Self(inline(s)))
     }
 }
 
@@ -76,7 +208,7 @@ mod tests {
 
     #[test]
     fn deserialize_node_id() {
-        for valid_node_id in ["n1", "12345678"] {
+        for valid_node_id in ["n1", "12345678", "a-much-longer-node-identifier"] {
             let id: NodeId = serde_json::from_str(&format!("\"{valid_node_id}\"")).unwrap();
             assert_eq!(id.to_string(), valid_node_id);
         }
@@ -84,7 +216,7 @@ mod tests {
 
     #[test]
     fn deserialize_node_id_failures() {
-        for invalid_node_id in ["toolong!!", "noäscii"] {
+        for invalid_node_id in ["", "noäscii", "no space"] {
             let id: Result<NodeId, _> = serde_json::from_str(&format!("\"{invalid_node_id}\""));
             assert!(
                 id.is_err(),
@@ -93,4 +225,32 @@ mod tests {
             );
         }
     }
+
+    #[test]
+    fn long_ids_compare_like_short_ones() {
+        let long: NodeId = "a-much-longer-node-identifier".parse().unwrap();
+        assert_eq!(long, "a-much-longer-node-identifier".parse().unwrap());
+        assert_eq!(long, NodeId::from_static("a-much-longer-node-identifier"));
+        assert!(node_id!("a") < long && long < node_id!("b"));
+        assert_eq!(long.as_str(), "a-much-longer-node-identifier");
+    }
+
+    #[test]
+    fn inline_ids_use_the_space_of_interned_ones() {
+        assert_eq!(
+            std::mem::size_of::<NodeId>(),
+            std::mem::size_of::<(usize, &str)>()
+        );
+        let short: NodeId = "a".repeat(INLINE_LENGTH).parse().unwrap();
+        assert!(matches!(short.0, Repr::Inline { .. }));
+        assert!(short < NodeId::from_static("b"));
+    }
+
+    #[test]
+    fn classifies_ids() {
+        assert_eq!(node_id!("c12").kind(), NodeKind::Client);
+        assert_eq!(node_id!("n0").kind(), NodeKind::Server);
+        assert_eq!(SEQ_KV.kind(), NodeKind::Service);
+        assert_eq!(node_id!("n").kind(), NodeKind::Service);
+    }
 }
@@ -1,30 +1,247 @@
 use std::{
-    io::Write,
-    sync::{mpsc, Arc},
+    io::{self, BufWriter, Write},
+    sync::{mpsc, Arc, Mutex},
+    thread::JoinHandle,
 };
 
-use crate::Logger;
+use serde::Serialize;
 
-pub(crate) fn spawn_output_thread(logger: Arc<Logger>) -> mpsc::SyncSender<String> {
-    let (tx, rx) = mpsc::sync_channel(100);
-    std::thread::spawn(move || stdout_writer(rx, logger));
-    tx
+use crate::{input::NodeInput, serialize_message, Envelope, Level, Logger, Message, NodeMetrics};
+
+/// How [crate::run_node] writes the node's messages.
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub struct OutputOptions {
+    /// How many messages may wait for being written (default: 100).
+    pub capacity: usize,
+    pub flush: FlushPolicy,
+    pub backpressure: Backpressure,
+}
+
+impl Default for OutputOptions {
+    fn default() -> Self {
+        Self {
+            capacity: 100,
+            flush: FlushPolicy::default(),
+            backpressure: Backpressure::default(),
+        }
+    }
+}
+
+/// When buffered output is flushed to STDOUT.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum FlushPolicy {
+    /// After writing all messages that were queued at once.
+    #[default]
+    Batch,
+    /// After every message.
+    Message,
+}
+
+/// What happens when a node sends faster than its output can be written.
+#[derive(PartialEq, Eq, Clone, Copy, Debug, Default)]
+pub enum Backpressure {
+    /// Sending blocks once [OutputOptions::capacity] messages are queued.
+    #[default]
+    Block,
+    /// The queue grows without limit and [OutputOptions::capacity] is
+    /// ignored.
+    Unbounded,
+}
+
+/// The sending half of a node's output channel.
+///
+/// [crate::run_node] uses a bounded channel to apply back-pressure (unless
+/// configured otherwise), the in-process [crate::sim::Simulation] an unbounded
+/// one. All clones share the [NodeMetrics] that count the sent messages.
+#[derive(Clone, Debug)]
+pub(crate) struct OutputSender {
+    channel: Channel,
+    metrics: Arc<Mutex<NodeMetrics>>,
 }
 
-pub fn stdout_writer(rx: mpsc::Receiver<String>, logger: Arc<Logger>) {
-    // This thread keeps a lock on stdout to prevent us from accidentally
-    // writing to stdout from other threads.
-    let mut stdout = std::io::stdout().lock();
-    loop {
-        let message = rx.recv().expect("reading from channel should succeed");
+/// A channel of serialized messages.
+#[derive(Clone, Debug)]
+pub(crate) enum Channel {
+    Bounded(mpsc::SyncSender<String>),
+    Unbounded(mpsc::Sender<String>),
+}
+
+impl Channel {
+    /// Queues `line` for writing, see [OutputSender::send].
+    pub(crate) fn send(&self, line: String) {
+        let _ = match self {
+            Self::Bounded(tx) => tx.send(line),
+            Self::Unbounded(tx) => tx.send(line),
+        };
+    }
+}
+
+impl OutputSender {
+    pub(crate) fn bounded(tx: mpsc::SyncSender<String>) -> Self {
+        Self::new(Channel::Bounded(tx))
+    }
+
+    pub(crate) fn unbounded(tx: mpsc::Sender<String>) -> Self {
+        Self::new(Channel::Unbounded(tx))
+    }
+
+    fn new(channel: Channel) -> Self {
+        Self {
+            channel,
+            metrics: Arc::default(),
+        }
+    }
 
-        // One message per line:
-        assert!(!message.contains('\n')); // XXX: move invariant to type, e.g. `OneLineString`
-        stdout
-            .write_all(message.as_bytes())
-            .expect("stdout should be writable");
-        stdout.write_all(b"\n").expect("stdout should be writable");
+    /// Serializes `message`, counts it and queues it for writing.
+    ///
+    /// Messages are discarded once the receiver is gone, i.e. after the
+    /// output was closed. [crate::run_node] shuts the node down in that case.
+    pub(crate) fn send<P: Serialize>(&self, message: &Message<P>) {
+        let line = serialize_message(message);
+        let envelope = Envelope::parse(&line).ok();
+        self.metrics
+            .lock()
+            .expect("lock should not be poisoned")
+            .record_sent(
+                line.len(),
+                message.header.dest,
+                envelope.as_ref().and_then(Envelope::message_type),
+            );
+        self.channel.send(line);
+    }
+
+    /// Sends to `tx` from now on and returns the previous channel.
+    ///
+    /// Used to record the output before it is written, the metrics stay
+    /// with this sender.
+    pub(crate) fn redirect(self, tx: mpsc::Sender<String>) -> (Self, Channel) {
+        let sender = Self {
+            channel: Channel::Unbounded(tx),
+            metrics: self.metrics,
+        };
+        (sender, self.channel)
+    }
+
+    pub(crate) fn metrics(&self) -> &Arc<Mutex<NodeMetrics>> {
+        &self.metrics
+    }
+}
+
+/// Spawns the thread writing to `writer`.
+///
+/// The thread ends after all senders were dropped and the remaining messages
+/// were written. If writing fails (e.g. because STDOUT was closed), it
+/// discards all further messages and sends [NodeInput::Shutdown] to `node_tx`.
+pub(crate) fn spawn_output_thread(
+    writer: impl Write + Send + 'static,
+    options: OutputOptions,
+    node_tx: mpsc::SyncSender<NodeInput>,
+    logger: Arc<Logger>,
+) -> (OutputSender, JoinHandle<()>) {
+    let (tx, rx) = match options.backpressure {
+        Backpressure::Block => {
+            let (tx, rx) = mpsc::sync_channel(options.capacity);
+            (OutputSender::bounded(tx), rx)
+        }
+        Backpressure::Unbounded => {
+            let (tx, rx) = mpsc::channel();
+            (OutputSender::unbounded(tx), rx)
+        }
+    };
+    let handle = std::thread::spawn(move || {
+        if let Err(error) = write_output(&rx, writer, options.flush, &logger) {
+            let text = format!("failed to write output, shutting down: {error}");
+            logger.log(Level::Warn, module_path!(), &text);
+            drop(rx);
+            // The node might have shut down already.
+            let _ = node_tx.send(NodeInput::Shutdown);
+        }
+    });
+    (tx, handle)
+}
+
+/// Writes messages from `rx` to `out`, one per line.
+///
+/// Whatever is queued when the thread wakes up is written as one batch, so a
+/// busy node needs far fewer system calls than messages.
+fn write_output(
+    rx: &mpsc::Receiver<String>,
+    out: impl Write,
+    flush: FlushPolicy,
+    logger: &Logger,
+) -> io::Result<()> {
+    let mut out = BufWriter::new(out);
+    let mut batch = Vec::new();
+    while let Ok(message) = rx.recv() {
+        batch.push(message);
+        batch.extend(rx.try_iter());
+        for message in &batch {
+            // One message per line:
+            assert!(!message.contains('\n')); // XXX: move invariant to type, e.g. `OneLineString`
+            out.write_all(message.as_bytes())?;
+            out.write_all(b"\n")?;
+            if flush == FlushPolicy::Message {
+                out.flush()?;
+            }
+        }
+        out.flush()?;
+        // Logging (to stderr) must not delay the output.
+        for message in batch.drain(..) {
+            logger.log_message('>', &message);
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Records every write and flush.
+    #[derive(Default)]
+    struct Recorder {
+        writes: Vec<String>,
+        flushes: usize,
+        fail: bool,
+    }
+
+    impl Write for &mut Recorder {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            if self.fail {
+                return Err(io::ErrorKind::BrokenPipe.into());
+            }
+            self.writes.push(String::from_utf8_lossy(buf).into_owned());
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            self.flushes += 1;
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn queued_messages_are_written_at_once() {
+        let (tx, rx) = mpsc::channel();
+        for i in 0..3 {
+            tx.send(format!("message {i}")).unwrap();
+        }
+        drop(tx);
+        let mut out = Recorder::default();
+        write_output(&rx, &mut out, FlushPolicy::Batch, &Logger::default()).unwrap();
+        assert_eq!(out.writes, ["message 0\nmessage 1\nmessage 2\n"]);
+        assert_eq!(out.flushes, 1);
+    }
 
-        logger.log(&format!("> {message}")); // logs to stderr
+    #[test]
+    fn write_errors_are_returned() {
+        let (tx, rx) = mpsc::channel();
+        tx.send("message".to_owned()).unwrap();
+        let mut out = Recorder {
+            fail: true,
+            ..Recorder::default()
+        };
+        let error = write_output(&rx, &mut out, FlushPolicy::Message, &Logger::default());
+        assert_eq!(error.unwrap_err().kind(), io::ErrorKind::BrokenPipe);
     }
 }
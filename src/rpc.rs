@@ -0,0 +1,198 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    fmt,
+    time::{Duration, Instant},
+};
+
+use anyhow::Result;
+use serde::Deserialize;
+
+use crate::{
+    deserialize_message, now, Envelope, ErrorCode, ErrorPayload, Message, MessageHeader, MessageId,
+};
+
+/// The outcome of an RPC: either the reply or an error.
+///
+/// Replies with `type` `error` are turned into `Err`. If no reply arrives
+/// before the RPC's timeout, a synthetic [ErrorCode::Timeout] error is
+/// produced.
+pub type RpcResult = Result<RpcReply, ErrorPayload>;
+
+/// Receives the outcome of an RPC sent with [crate::MessageTransmitter::call_with].
+pub type RpcCallback = Box<dyn FnOnce(RpcResult) + Send>;
+
+/// A (non-error) reply to an RPC.
+#[derive(Clone, Debug)]
+pub struct RpcReply {
+    header: MessageHeader,
+    message: String,
+}
+
+impl RpcReply {
+    pub fn header(&self) -> &MessageHeader {
+        &self.header
+    }
+
+    /// Deserializes the reply into a typed message.
+    ///
+    /// The payload may borrow from the reply.
+    pub fn deserialize<'a, P: Deserialize<'a>>(&'a self) -> Result<Message<P>> {
+        deserialize_message(&self.message)
+    }
+}
+
+/// An RPC's outcome as passed to [crate::NodeState::rpc_reply].
+#[derive(Debug)]
+pub struct RpcResponse {
+    /// The [MessageId] of the request (as returned by
+    /// [crate::MessageTransmitter::call]).
+    pub request_id: MessageId,
+    pub result: RpcResult,
+}
+
+/// Where to deliver an RPC's outcome.
+pub(crate) enum Completion {
+    Node,
+    Callback(RpcCallback),
+}
+
+impl fmt::Debug for Completion {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Completion::Node => write!(f, "Node"),
+            Completion::Callback(_) => write!(f, "Callback(..)"),
+        }
+    }
+}
+
+#[derive(Debug)]
+struct PendingRpc {
+    sent_at: Instant,
+    deadline: Option<Instant>,
+    completion: Completion,
+}
+
+/// All RPCs still waiting for a reply, keyed by their request's [MessageId].
+#[derive(Debug, Default)]
+pub(crate) struct PendingRpcs {
+    entries: HashMap<MessageId, PendingRpc>,
+    deadlines: BTreeSet<(Instant, MessageId)>,
+}
+
+impl PendingRpcs {
+    pub(crate) fn insert(
+        &mut self,
+        request_id: MessageId,
+        timeout: Option<Duration>,
+        completion: Completion,
+    ) {
+        let sent_at = now();
+        let deadline = timeout.map(|timeout| sent_at + timeout);
+        if let Some(deadline) = deadline {
+            self.deadlines.insert((deadline, request_id));
+        }
+        self.entries.insert(
+            request_id,
+            PendingRpc {
+                sent_at,
+                deadline,
+                completion,
+            },
+        );
+    }
+
+    /// Removes the RPC a message with `in_reply_to` belongs to.
+    ///
+    /// Also returns when the RPC was sent.
+    pub(crate) fn complete(&mut self, in_reply_to: MessageId) -> Option<(Completion, Instant)> {
+        let rpc = self.entries.remove(&in_reply_to)?;
+        if let Some(deadline) = rpc.deadline {
+            self.deadlines.remove(&(deadline, in_reply_to));
+        }
+        Some((rpc.completion, rpc.sent_at))
+    }
+
+    /// Removes all RPCs whose deadline is not after `now`.
+    pub(crate) fn expire(&mut self, now: Instant) -> Vec<(MessageId, Completion)> {
+        let mut expired = Vec::new();
+        while let Some(&(deadline, request_id)) = self.deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop_first();
+            if let Some(rpc) = self.entries.remove(&request_id) {
+                expired.push((request_id, rpc.completion));
+            }
+        }
+        expired
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+}
+
+/// Turns a reply to a pending RPC into its outcome.
+///
+/// An `error` reply that doesn't parse is an indefinite [ErrorCode::Crash],
+/// as we can't tell what went wrong.
+pub(crate) fn reply_result(envelope: &Envelope) -> RpcResult {
+    match envelope.message_type() {
+        Some("error") => {
+            Err(envelope
+                .payload::<ErrorPayload>()
+                .unwrap_or_else(|err| ErrorPayload {
+                    code: ErrorCode::Crash,
+                    text: format!("invalid error reply: {err:#}"),
+                }))
+        }
+        _ => Ok(RpcReply {
+            header: envelope.header,
+            message: envelope.raw().to_owned(),
+        }),
+    }
+}
+
+pub(crate) fn timeout_error() -> ErrorPayload {
+    ErrorPayload {
+        code: ErrorCode::Timeout,
+        text: "no reply received before the RPC timed out".to_owned(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parse_error_reply() {
+        let json_string = r#"{
+            "src": "seq-kv",
+            "dest": "n1",
+            "body": {"type": "error", "in_reply_to": 4, "code": 22, "text": "mismatch"}
+        }"#;
+        let envelope = Envelope::parse(json_string).unwrap();
+        let result = reply_result(&envelope);
+        assert_eq!(result.unwrap_err().code, ErrorCode::PreconditionFailed);
+
+        let invalid = r#"{"src":"n2","dest":"n1","body":{"type":"error","in_reply_to":5}}"#;
+        let error = reply_result(&Envelope::parse(invalid).unwrap()).unwrap_err();
+        assert_eq!(error.code, ErrorCode::Crash);
+        assert!(error.text.starts_with("invalid error reply"));
+    }
+
+    #[test]
+    fn expire_pending_rpcs() {
+        let mut rpcs = PendingRpcs::default();
+        rpcs.insert(MessageId::from(1), Some(Duration::ZERO), Completion::Node);
+        rpcs.insert(MessageId::from(2), None, Completion::Node);
+        assert!(rpcs.next_deadline().is_some());
+
+        let expired = rpcs.expire(Instant::now());
+        assert_eq!(expired.len(), 1);
+        assert_eq!(expired[0].0, MessageId::from(1));
+        assert!(rpcs.next_deadline().is_none());
+        assert!(rpcs.complete(MessageId::from(2)).is_some());
+        assert!(rpcs.complete(MessageId::from(2)).is_none());
+    }
+}
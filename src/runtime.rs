@@ -0,0 +1,334 @@
+use std::{
+    sync::{Arc, Mutex},
+    time::Instant,
+};
+
+use anyhow::Result;
+use serde::Serialize;
+
+use crate::{
+    error_reply,
+    init::Initialized,
+    now,
+    output::OutputSender,
+    reply_without_id,
+    rpc::{self, Completion, PendingRpcs},
+    AfterInitTransition, Envelope, ErrorCode, ErrorPayload, ErrorPolicy, InitializingNode, Level,
+    Logger, MessageHeader, MessageId, MessageTransmitter, NodeId, NodeMetrics, NodeState,
+    RequestHeader, RpcResponse, RpcResult, TimerId, Timers, Transition,
+};
+
+/// Drives a node: routes its inputs and keeps track of its timers.
+///
+/// This is everything [crate::run_node] does besides reading and writing
+/// messages.
+pub(crate) struct Runtime {
+    stage: Stage,
+    rpcs: Arc<Mutex<PendingRpcs>>,
+    timers: Timers,
+    node_wake_up: Option<Instant>,
+    output_tx: OutputSender,
+    error_policy: ErrorPolicy,
+    logger: Arc<Logger>,
+    metrics: Arc<Mutex<NodeMetrics>>,
+    answer_metrics_requests: bool,
+}
+
+/// The node, or what stands in for it until it is initialized.
+enum Stage {
+    Initializing(InitializingNode),
+    Running(Box<dyn NodeState>),
+}
+
+impl Stage {
+    fn next_wake_up(&self) -> Option<Instant> {
+        match self {
+            Stage::Initializing(_) => None,
+            Stage::Running(node) => node.next_wake_up(),
+        }
+    }
+}
+
+impl Runtime {
+    pub(crate) fn new(
+        output_tx: OutputSender,
+        after_init: AfterInitTransition,
+        error_policy: ErrorPolicy,
+        logger: Arc<Logger>,
+    ) -> Self {
+        let init_logger = Arc::clone(&logger);
+        Self::with_stage(
+            output_tx,
+            error_policy,
+            logger,
+            |output_tx, rpcs, timers| {
+                Stage::Initializing(InitializingNode::new(
+                    output_tx,
+                    rpcs,
+                    timers,
+                    after_init,
+                    init_logger,
+                ))
+            },
+        )
+    }
+
+    /// Creates a runtime for a node that doesn't need an `init` message, e.g.
+    /// one of Maelstrom's services.
+    pub(crate) fn started(
+        node_id: NodeId,
+        output_tx: OutputSender,
+        make_node: impl FnOnce(MessageTransmitter<()>) -> Box<dyn NodeState>,
+        error_policy: ErrorPolicy,
+        logger: Arc<Logger>,
+    ) -> Self {
+        logger.set_node_id(node_id);
+        Self::with_stage(
+            output_tx,
+            error_policy,
+            logger,
+            |output_tx, rpcs, timers| {
+                Stage::Running(make_node(MessageTransmitter::new(
+                    node_id, output_tx, rpcs, timers,
+                )))
+            },
+        )
+    }
+
+    fn with_stage(
+        output_tx: OutputSender,
+        error_policy: ErrorPolicy,
+        logger: Arc<Logger>,
+        make_stage: impl FnOnce(OutputSender, Arc<Mutex<PendingRpcs>>, Timers) -> Stage,
+    ) -> Self {
+        let rpcs = Arc::new(Mutex::new(PendingRpcs::default()));
+        let timers = Timers::default();
+        let stage = make_stage(output_tx.clone(), Arc::clone(&rpcs), timers.clone());
+        Self {
+            node_wake_up: stage.next_wake_up(),
+            stage,
+            rpcs,
+            timers,
+            metrics: Arc::clone(output_tx.metrics()),
+            output_tx,
+            error_policy,
+            logger,
+            answer_metrics_requests: false,
+        }
+    }
+
+    /// Makes the runtime reply to `metrics` requests instead of passing them
+    /// to the node.
+    pub(crate) fn answer_metrics_requests(&mut self) {
+        self.answer_metrics_requests = true;
+    }
+
+    /// Returns a snapshot of the node's metrics.
+    pub(crate) fn metrics(&self) -> NodeMetrics {
+        self.lock_metrics().clone()
+    }
+
+    /// Handles an incoming message.
+    ///
+    /// Replies to pending RPCs are routed to their completion, everything else
+    /// goes to [NodeState::handle]. Messages received before `init` are
+    /// handled once the node is initialized.
+    pub(crate) fn handle_message(&mut self, message: &str) -> Result<()> {
+        let envelope = Envelope::parse(message).ok();
+        self.lock_metrics()
+            .record_received(message, envelope.as_ref().and_then(Envelope::message_type));
+        self.dispatch(message, envelope)
+    }
+
+    fn dispatch(&mut self, message: &str, envelope: Option<Envelope>) -> Result<()> {
+        if let Stage::Initializing(node) = &mut self.stage {
+            return match node.handle(message, envelope.as_ref())? {
+                Some(initialized) => self.start(initialized),
+                None => Ok(()),
+            };
+        }
+        let Some(envelope) = envelope else {
+            // Not a message at all, let the node decide what to do.
+            return self.transition_for(None, |node| node.handle(message));
+        };
+        if let Some((request_id, completion, result)) = self.take_reply(&envelope) {
+            return self.complete(request_id, completion, result);
+        }
+        let header = envelope.header;
+        if envelope.message_type() == Some("init") {
+            if let Ok(request) = header.request() {
+                let error = ErrorPayload {
+                    code: ErrorCode::PreconditionFailed,
+                    text: "node is already initialized".to_owned(),
+                };
+                self.output_tx.send(&error_reply(&request, error));
+            }
+            return Ok(());
+        }
+        if self.answer_metrics_requests && envelope.message_type() == Some("metrics") {
+            if let Ok(request) = header.request() {
+                return self.reply_metrics(&request);
+            }
+        }
+        self.transition_for(Some(&header), |node| node.handle_envelope(envelope))
+    }
+
+    /// Continues with the initialized node and lets it handle the messages
+    /// received before `init`.
+    fn start(&mut self, initialized: Initialized) -> Result<()> {
+        self.stage = Stage::Running(initialized.node);
+        self.node_wake_up = self.stage.next_wake_up();
+        for message in initialized.deferred {
+            self.dispatch(&message, Envelope::parse(&message).ok())?;
+        }
+        Ok(())
+    }
+
+    /// Handles a wake up call from the timer thread.
+    pub(crate) fn wake_up(&mut self) -> Result<()> {
+        let now = now();
+        let expired = self.lock_rpcs().expire(now);
+        for (request_id, completion) in expired {
+            self.logger.log(
+                Level::Warn,
+                module_path!(),
+                &format!("rpc {request_id:?} timed out"),
+            );
+            self.lock_metrics().rpc_timeouts += 1;
+            self.complete(request_id, completion, Err(rpc::timeout_error()))?;
+        }
+
+        for timer in self.timers.expire(now) {
+            self.transition(|node| node.wake_up(timer))?;
+        }
+
+        if self.node_wake_up.is_some_and(|instant| instant <= now) {
+            self.transition(|node| node.wake_up(TimerId::NEXT_WAKE_UP))?;
+        }
+        Ok(())
+    }
+
+    /// Lets the node know it is about to stop and logs its metrics.
+    pub(crate) fn shutdown(&mut self) {
+        if let Stage::Running(node) = &mut self.stage {
+            node.on_shutdown();
+        }
+        let metrics = self.metrics();
+        self.logger
+            .log(Level::Info, module_path!(), &format!("metrics:\n{metrics}"));
+    }
+
+    /// Returns when [Runtime::wake_up] should be called next.
+    pub(crate) fn next_wake_up(&self) -> Option<Instant> {
+        [
+            self.node_wake_up,
+            self.lock_rpcs().next_deadline(),
+            self.timers.next_deadline(),
+        ]
+        .into_iter()
+        .flatten()
+        .min()
+    }
+
+    fn take_reply(&self, envelope: &Envelope) -> Option<(MessageId, Completion, RpcResult)> {
+        let request_id = envelope.header.in_reply_to?;
+        let (completion, sent_at) = self.lock_rpcs().complete(request_id)?;
+        self.lock_metrics()
+            .rpc_round_trip
+            .record(now().saturating_duration_since(sent_at));
+        Some((request_id, completion, rpc::reply_result(envelope)))
+    }
+
+    fn complete(
+        &mut self,
+        request_id: MessageId,
+        completion: Completion,
+        result: RpcResult,
+    ) -> Result<()> {
+        match completion {
+            Completion::Node => {
+                self.transition(|node| node.rpc_reply(RpcResponse { request_id, result }))
+            }
+            Completion::Callback(on_reply) => {
+                on_reply(result);
+                Ok(())
+            }
+        }
+    }
+
+    fn transition(&mut self, f: impl FnOnce(&mut dyn NodeState) -> Transition) -> Result<()> {
+        self.transition_for(None, f)
+    }
+
+    /// Applies a transition, handling errors according to the
+    /// [ErrorPolicy].
+    ///
+    /// `request` is the header of the message that is being handled (if any).
+    fn transition_for(
+        &mut self,
+        request: Option<&MessageHeader>,
+        f: impl FnOnce(&mut dyn NodeState) -> Transition,
+    ) -> Result<()> {
+        let started = Instant::now();
+        let transition = match &mut self.stage {
+            Stage::Running(node) => f(node.as_mut()),
+            // Timers and RPCs only exist once the node is initialized, so
+            // there is nothing to do before.
+            Stage::Initializing(_) => return Ok(()),
+        };
+        self.lock_metrics()
+            .handler_latency
+            .record(started.elapsed());
+        match transition {
+            Ok(Some(node)) => self.stage = Stage::Running(node),
+            Ok(None) => (),
+            Err(error) if self.error_policy == ErrorPolicy::Crash => return Err(error),
+            Err(error) => {
+                self.logger.log(
+                    Level::Error,
+                    module_path!(),
+                    &format!("handler failed: {error:#}"),
+                );
+                if self.error_policy == ErrorPolicy::ReplyError {
+                    if let Some(Ok(request)) = request.map(MessageHeader::request) {
+                        self.reply_error(&request, error);
+                    }
+                }
+            }
+        }
+        self.node_wake_up = self.stage.next_wake_up();
+        Ok(())
+    }
+
+    fn reply_error(&self, request: &RequestHeader, error: anyhow::Error) {
+        let error = match error.downcast::<ErrorPayload>() {
+            Ok(error) => error,
+            Err(error) => ErrorPayload {
+                code: ErrorCode::Crash,
+                text: format!("{error:#}"),
+            },
+        };
+        self.output_tx.send(&error_reply(request, error));
+    }
+
+    fn reply_metrics(&self, request: &RequestHeader) -> Result<()> {
+        #[derive(Clone, Serialize)]
+        #[serde(tag = "type", rename = "metrics_ok")]
+        struct MetricsOk {
+            metrics: NodeMetrics,
+        }
+        let reply = MetricsOk {
+            metrics: self.metrics(),
+        };
+        self.output_tx.send(&reply_without_id(request, reply));
+        Ok(())
+    }
+
+    fn lock_metrics(&self) -> std::sync::MutexGuard<'_, NodeMetrics> {
+        self.metrics.lock().expect("lock should not be poisoned")
+    }
+
+    fn lock_rpcs(&self) -> std::sync::MutexGuard<'_, PendingRpcs> {
+        self.rpcs.lock().expect("lock should not be poisoned")
+    }
+}
@@ -0,0 +1,389 @@
+//! In-process versions of Maelstrom's services.
+//!
+//! See <https://github.com/jepsen-io/maelstrom/blob/main/doc/services.md>.
+//!
+//! [KvService] answers `read`, `write` and `cas` requests like Maelstrom's
+//! `seq-kv`, `lin-kv` and `lww-kv`, so nodes talking to those services can be
+//! tested in a [crate::sim::Simulation] (see
+//! [crate::sim::Simulation::add_kv_services]).
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    time::Duration,
+};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+
+use crate::{
+    rng::Rng,
+    typed::{parse_request, ParsedRequest},
+    Envelope, ErrorCode, ErrorPayload, Message, MessageTransmitter, NodeId, NodeState,
+    RequestHeader, TimerId, Transition,
+};
+
+pub use crate::node_id::{LIN_KV, LWW_KV, SEQ_KV};
+
+/// How many replicas [KvModel::LastWriteWins] keeps.
+const LWW_REPLICAS: usize = 3;
+
+/// How often [KvModel::LastWriteWins] replicas exchange their values.
+const LWW_SYNC_INTERVAL: Duration = Duration::from_millis(100);
+
+/// The consistency model of a [KvService].
+#[derive(PartialEq, Eq, Clone, Copy, Debug)]
+pub enum KvModel {
+    /// Like `seq-kv`: reads may return stale values, but every client observes
+    /// a single order of operations that respects its own order.
+    Sequential,
+    /// Like `lin-kv`: every operation observes the latest value.
+    Linearizable,
+    /// Like `lww-kv`: operations go to one of several replicas, which converge
+    /// to the last write eventually.
+    LastWriteWins,
+}
+
+impl KvModel {
+    /// The node id Maelstrom uses for this kind of service.
+    pub fn node_id(self) -> NodeId {
+        match self {
+            KvModel::Sequential => SEQ_KV,
+            KvModel::Linearizable => LIN_KV,
+            KvModel::LastWriteWins => LWW_KV,
+        }
+    }
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestPayload {
+    Read {
+        key: Value,
+    },
+    Write {
+        key: Value,
+        value: Value,
+    },
+    Cas {
+        key: Value,
+        from: Value,
+        to: Value,
+        #[serde(default)]
+        create_if_not_exists: bool,
+    },
+}
+
+#[derive(PartialEq, Eq, Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+#[allow(clippy::enum_variant_names)]
+enum ResponsePayload {
+    ReadOk { value: Value },
+    WriteOk,
+    CasOk,
+}
+
+/// A key-value store service following Maelstrom's protocol.
+#[derive(Debug)]
+pub struct KvService {
+    tx: MessageTransmitter<ResponsePayload>,
+    store: Store,
+    sync_timer: Option<TimerId>,
+}
+
+impl KvService {
+    /// Creates a service with the given consistency model.
+    ///
+    /// `seed` determines which stale values (or replicas) requests observe.
+    pub fn new(model: KvModel, tx: MessageTransmitter<()>, seed: u64) -> Self {
+        let rng = Rng::new(seed);
+        let (store, sync_timer) = match model {
+            KvModel::Sequential => (Store::Sequential(SeqStore::new(rng)), None),
+            KvModel::Linearizable => (Store::Linearizable(BTreeMap::new()), None),
+            KvModel::LastWriteWins => (
+                Store::LastWriteWins(LwwStore::new(rng)),
+                Some(tx.timers().schedule_every(LWW_SYNC_INTERVAL)),
+            ),
+        };
+        Self {
+            tx: tx.into(),
+            store,
+            sync_timer,
+        }
+    }
+
+    fn handle_request(&mut self, header: RequestHeader, payload: RequestPayload) {
+        let client = header.src;
+        let result = match payload {
+            RequestPayload::Read { key } => self
+                .store
+                .read(client, &key.to_string())
+                .map(|value| ResponsePayload::ReadOk { value })
+                .ok_or_else(|| key_does_not_exist(&key)),
+            RequestPayload::Write { key, value } => {
+                self.store.write(client, &key.to_string(), value);
+                Ok(ResponsePayload::WriteOk)
+            }
+            RequestPayload::Cas {
+                key,
+                from,
+                to,
+                create_if_not_exists,
+            } => {
+                let cas = CompareAndSwap {
+                    from,
+                    to,
+                    create_if_not_exists,
+                };
+                self.store
+                    .cas(client, &key.to_string(), cas)
+                    .map(|()| ResponsePayload::CasOk)
+                    .map_err(|error| match error {
+                        CasError::KeyDoesNotExist => key_does_not_exist(&key),
+                        CasError::PreconditionFailed { expected, actual } => ErrorPayload {
+                            code: ErrorCode::PreconditionFailed,
+                            text: format!("expected {expected}, but had {actual}"),
+                        },
+                    })
+            }
+        };
+        match result {
+            Ok(payload) => {
+                self.tx.reply(&header, payload);
+            }
+            Err(ErrorPayload { code, text }) => {
+                self.tx.reply_error(&header, code, text);
+            }
+        }
+    }
+}
+
+impl NodeState for KvService {
+    fn handle(&mut self, request: &str) -> Transition {
+        self.handle_envelope(Envelope::parse(request)?)
+    }
+
+    fn handle_envelope(&mut self, request: Envelope<'_>) -> Transition {
+        match parse_request(&request) {
+            ParsedRequest::Valid(Message { header, payload }) => {
+                // Notifications leave nothing to reply to.
+                if let Ok(header) = header.request() {
+                    self.handle_request(header, payload);
+                }
+            }
+            ParsedRequest::Ignored => (),
+            ParsedRequest::Invalid(header, code, text) => {
+                self.tx.reply_error(&header, code, text);
+            }
+        }
+        Ok(None)
+    }
+
+    fn wake_up(&mut self, timer: TimerId) -> Transition {
+        if Some(timer) == self.sync_timer {
+            if let Store::LastWriteWins(store) = &mut self.store {
+                store.sync();
+            }
+        }
+        Ok(None)
+    }
+}
+
+fn key_does_not_exist(key: &Value) -> ErrorPayload {
+    ErrorPayload {
+        code: ErrorCode::KeyDoesNotExist,
+        text: format!("key {key} does not exist"),
+    }
+}
+
+struct CompareAndSwap {
+    from: Value,
+    to: Value,
+    create_if_not_exists: bool,
+}
+
+impl CompareAndSwap {
+    /// Checks the precondition against the `current` value.
+    fn check(&self, current: Option<&Value>) -> Result<(), CasError> {
+        match current {
+            None if self.create_if_not_exists => Ok(()),
+            None => Err(CasError::KeyDoesNotExist),
+            Some(current) if *current == self.from => Ok(()),
+            Some(current) => Err(CasError::PreconditionFailed {
+                expected: self.from.clone(),
+                actual: current.clone(),
+            }),
+        }
+    }
+}
+
+enum CasError {
+    KeyDoesNotExist,
+    PreconditionFailed { expected: Value, actual: Value },
+}
+
+#[derive(Debug)]
+enum Store {
+    Sequential(SeqStore),
+    Linearizable(BTreeMap<String, Value>),
+    LastWriteWins(LwwStore),
+}
+
+impl Store {
+    fn read(&mut self, client: NodeId, key: &str) -> Option<Value> {
+        match self {
+            Store::Sequential(store) => store.read(client, key),
+            Store::Linearizable(values) => values.get(key).cloned(),
+            Store::LastWriteWins(store) => store.read(key),
+        }
+    }
+
+    fn write(&mut self, client: NodeId, key: &str, value: Value) {
+        match self {
+            Store::Sequential(store) => store.write(client, key, value),
+            Store::Linearizable(values) => {
+                values.insert(key.to_owned(), value);
+            }
+            Store::LastWriteWins(store) => store.write(key, value),
+        }
+    }
+
+    fn cas(&mut self, client: NodeId, key: &str, cas: CompareAndSwap) -> Result<(), CasError> {
+        match self {
+            Store::Sequential(store) => store.cas(client, key, cas),
+            Store::Linearizable(values) => {
+                cas.check(values.get(key))?;
+                values.insert(key.to_owned(), cas.to);
+                Ok(())
+            }
+            Store::LastWriteWins(store) => store.cas(key, cas),
+        }
+    }
+}
+
+/// A sequentially consistent store.
+///
+/// All writes are totally ordered by a version number. Each client may read
+/// any version between the latest one it observed and the current one, so
+/// reads can be stale but never go back in time for that client.
+#[derive(Debug)]
+struct SeqStore {
+    rng: Rng,
+    version: u64,
+    history: HashMap<String, Vec<(u64, Value)>>,
+    observed: HashMap<NodeId, u64>,
+}
+
+impl SeqStore {
+    fn new(rng: Rng) -> Self {
+        Self {
+            rng,
+            version: 0,
+            history: HashMap::new(),
+            observed: HashMap::new(),
+        }
+    }
+
+    fn value_at(&self, key: &str, version: u64) -> Option<&Value> {
+        let history = self.history.get(key)?;
+        let idx = history.partition_point(|(v, _)| *v <= version);
+        idx.checked_sub(1).map(|idx| &history[idx].1)
+    }
+
+    fn read(&mut self, client: NodeId, key: &str) -> Option<Value> {
+        let observed = self.observed.get(&client).copied().unwrap_or_default();
+        let version = observed + self.rng.below(self.version - observed + 1);
+        self.observed.insert(client, version);
+        self.value_at(key, version).cloned()
+    }
+
+    fn write(&mut self, client: NodeId, key: &str, value: Value) {
+        self.version += 1;
+        self.history
+            .entry(key.to_owned())
+            .or_default()
+            .push((self.version, value));
+        self.observed.insert(client, self.version);
+        self.prune(key);
+    }
+
+    fn cas(&mut self, client: NodeId, key: &str, cas: CompareAndSwap) -> Result<(), CasError> {
+        // Compare-and-swap always acts on the current value.
+        self.observed.insert(client, self.version);
+        cas.check(self.value_at(key, self.version))?;
+        self.write(client, key, cas.to);
+        Ok(())
+    }
+
+    /// Drops versions of `key` no client can read anymore.
+    fn prune(&mut self, key: &str) {
+        let oldest_observed = self.observed.values().copied().min().unwrap_or_default();
+        if let Some(history) = self.history.get_mut(key) {
+            let visible = history.partition_point(|(v, _)| *v <= oldest_observed);
+            history.drain(..visible.saturating_sub(1));
+        }
+    }
+}
+
+/// A store with several replicas using last-write-wins conflict resolution.
+///
+/// Each operation goes to a random replica. Replicas sync periodically.
+#[derive(Debug)]
+struct LwwStore {
+    rng: Rng,
+    clock: u64,
+    replicas: Vec<BTreeMap<String, (u64, Value)>>,
+}
+
+impl LwwStore {
+    fn new(rng: Rng) -> Self {
+        Self {
+            rng,
+            clock: 0,
+            replicas: vec![BTreeMap::new(); LWW_REPLICAS],
+        }
+    }
+
+    fn replica(&mut self) -> usize {
+        self.rng.below(self.replicas.len() as u64) as usize
+    }
+
+    fn read(&mut self, key: &str) -> Option<Value> {
+        let replica = self.replica();
+        self.replicas[replica]
+            .get(key)
+            .map(|(_, value)| value.clone())
+    }
+
+    fn write(&mut self, key: &str, value: Value) {
+        let replica = self.replica();
+        self.write_to(replica, key, value);
+    }
+
+    fn write_to(&mut self, replica: usize, key: &str, value: Value) {
+        self.clock += 1;
+        self.replicas[replica].insert(key.to_owned(), (self.clock, value));
+    }
+
+    fn cas(&mut self, key: &str, cas: CompareAndSwap) -> Result<(), CasError> {
+        let replica = self.replica();
+        cas.check(self.replicas[replica].get(key).map(|(_, value)| value))?;
+        self.write_to(replica, key, cas.to);
+        Ok(())
+    }
+
+    /// Merges all replicas, keeping the last write for every key.
+    fn sync(&mut self) {
+        let mut merged: BTreeMap<String, (u64, Value)> = BTreeMap::new();
+        for replica in &self.replicas {
+            for (key, (timestamp, value)) in replica {
+                if merged.get(key).is_none_or(|(t, _)| t < timestamp) {
+                    merged.insert(key.clone(), (*timestamp, value.clone()));
+                }
+            }
+        }
+        for replica in &mut self.replicas {
+            replica.clone_from(&merged);
+        }
+    }
+}
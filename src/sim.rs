@@ -0,0 +1,472 @@
+//! A deterministic, in-process cluster simulator.
+//!
+//! [Simulation] runs several nodes in the current thread, routes their
+//! messages through a simulated network and drives their timers from a virtual
+//! clock. Together with a fixed seed, this makes node behavior reproducible in
+//! plain `cargo test`s without a Maelstrom installation:
+//!
+//! ```
+//! use fly_into_the_maelstrom::{sim::*, *};
+//! use serde_json::json;
+//!
+//! struct EchoNode {
+//!     tx: MessageTransmitter<serde_json::Value>,
+//! }
+//!
+//! impl NodeState for EchoNode {
+//!     fn handle(&mut self, request: &str) -> Transition {
+//!         let Message { header, payload } = deserialize_message::<serde_json::Value>(request)?;
+//!         let echo = payload["echo"].clone();
+//!         self.tx.reply(&header.request()?, json!({"type": "echo_ok", "echo": echo}));
+//!         Ok(None)
+//!     }
+//! }
+//!
+//! let mut sim = Simulation::new(1, SimulationOptions::default(), Box::new(|_, tx| {
+//!     Ok(Box::new(EchoNode { tx: tx.into() }))
+//! }))
+//! .unwrap();
+//! let client = "c1".parse().unwrap();
+//! let n0 = sim.node_ids()[0];
+//! sim.send(client, n0, json!({"type": "echo", "echo": "hello"}));
+//! sim.run_until_idle().unwrap();
+//!
+//! let replies = sim.take_client_messages::<serde_json::Value>(client).unwrap();
+//! assert_eq!(replies[0].payload["echo"], "hello");
+//! ```
+
+use std::{
+    cmp::Reverse,
+    collections::{BTreeMap, BinaryHeap},
+    ops::RangeFrom,
+    rc::Rc,
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{anyhow, bail, Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock, deserialize_header, deserialize_message,
+    output::OutputSender,
+    rng::Rng,
+    runtime::Runtime,
+    serialize_message,
+    services::{KvModel, KvService},
+    AfterInitTransition, ErrorPolicy, Logger, Message, MessageHeader, MessageId,
+    MessageTransmitter, NodeId, NodeMetrics, NodeState,
+};
+
+/// The client sending `init` messages. Its `init_ok` replies are recorded
+/// like those to any other client.
+const INIT_CLIENT: NodeId = crate::node_id!("c0");
+
+/// How much simulated time [Simulation::run_until_idle] waits for the nodes
+/// to become idle.
+pub const IDLE_TIME_LIMIT: Duration = Duration::from_secs(10 * 60);
+
+/// How long a message takes from one node to another.
+#[derive(PartialEq, Clone, Copy, Debug)]
+pub enum Latency {
+    Constant(Duration),
+    Uniform { min: Duration, max: Duration },
+    Exponential { mean: Duration },
+}
+
+impl Default for Latency {
+    fn default() -> Self {
+        Latency::Constant(Duration::ZERO)
+    }
+}
+
+impl Latency {
+    fn sample(&self, rng: &mut Rng) -> Duration {
+        match *self {
+            Latency::Constant(latency) => latency,
+            Latency::Uniform { min, max } => {
+                min + (max.saturating_sub(min)).mul_f64(rng.next_f64())
+            }
+            Latency::Exponential { mean } => mean.mul_f64(-(1.0 - rng.next_f64()).ln()),
+        }
+    }
+}
+
+/// Parameters of a [Simulation].
+#[derive(PartialEq, Clone, Debug, Default)]
+pub struct SimulationOptions {
+    /// The seed for all random decisions, e.g. message latencies.
+    pub seed: u64,
+    /// The latency of messages between nodes (and clients).
+    pub latency: Latency,
+    /// What to do when a node fails to handle its input.
+    pub error_policy: ErrorPolicy,
+    /// See [crate::NodeOptions::answer_metrics_requests].
+    pub answer_metrics_requests: bool,
+}
+
+/// An in-process cluster of nodes with a simulated network and clock.
+///
+/// Nodes are named `n0`, `n1`, ... and get initialized just like in
+/// [crate::run_node]. Services can be added with [Simulation::add_service].
+/// Messages to anything that isn't a node or service are considered to be sent
+/// to clients and can be inspected with [Simulation::take_client_messages].
+/// The `init` messages come from client `c0`, so its messages start with one
+/// `init_ok` reply per node.
+pub struct Simulation {
+    now: Instant,
+    rng: Rng,
+    latency: Latency,
+    error_policy: ErrorPolicy,
+    answer_metrics_requests: bool,
+    node_ids: Box<[NodeId]>,
+    nodes: BTreeMap<NodeId, SimNode>,
+    in_flight: BinaryHeap<Reverse<InFlight>>,
+    next_seq: u64,
+    client_msg_ids: RangeFrom<u64>,
+    client_messages: Vec<String>,
+}
+
+struct SimNode {
+    runtime: Runtime,
+    output_rx: mpsc::Receiver<String>,
+    next_wake_up: Option<Instant>,
+}
+
+/// A message on the wire, ordered by delivery time (and then by sending order).
+#[derive(PartialEq, Eq, PartialOrd, Ord)]
+struct InFlight {
+    deliver_at: Instant,
+    seq: u64,
+    dest: NodeId,
+    message: String,
+}
+
+impl Simulation {
+    /// Creates `node_count` nodes and sends them their `init` message.
+    ///
+    /// Fails if a node fails to initialize.
+    pub fn new(
+        node_count: usize,
+        options: SimulationOptions,
+        after_init: AfterInitTransition,
+    ) -> Result<Self> {
+        let after_init: Rc<AfterInitTransition> = Rc::new(after_init);
+        let node_ids: Box<[NodeId]> = (0..node_count)
+            .map(|idx| format!("n{idx}").parse().expect("node id should be valid"))
+            .collect();
+        let mut sim = Self {
+            now: Instant::now(),
+            rng: Rng::new(options.seed),
+            latency: options.latency,
+            error_policy: options.error_policy,
+            answer_metrics_requests: options.answer_metrics_requests,
+            node_ids: node_ids.clone(),
+            nodes: BTreeMap::new(),
+            in_flight: BinaryHeap::new(),
+            next_seq: 0,
+            client_msg_ids: 0..,
+            client_messages: Vec::new(),
+        };
+
+        for &node_id in node_ids.iter() {
+            let after_init = Rc::clone(&after_init);
+            sim.add_node(node_id, Box::new(move |init, tx| after_init(init, tx)));
+        }
+        // Maelstrom's clients only start once all nodes are initialized, so we
+        // deliver the `init` messages right away.
+        for &node_id in node_ids.iter() {
+            let init = Message {
+                header: MessageHeader {
+                    src: INIT_CLIENT,
+                    dest: node_id,
+                    msg_id: Some(sim.next_client_msg_id()),
+                    in_reply_to: None,
+                },
+                payload: InitRequest::Init {
+                    node_id,
+                    node_ids: node_ids.clone(),
+                },
+            };
+            sim.deliver(node_id, &serialize_message(&init))
+                .with_context(|| format!("failed to initialize {node_id}"))?;
+        }
+        Ok(sim)
+    }
+
+    fn add_node(&mut self, node_id: NodeId, after_init: AfterInitTransition) {
+        let (output_tx, output_rx) = mpsc::channel();
+        let mut runtime = Runtime::new(
+            OutputSender::unbounded(output_tx),
+            after_init,
+            self.error_policy,
+            Arc::new(Logger::from_env()),
+        );
+        if self.answer_metrics_requests {
+            runtime.answer_metrics_requests();
+        }
+        self.insert_node(node_id, runtime, output_rx);
+    }
+
+    /// Adds a service like Maelstrom's `seq-kv` that nodes and clients can
+    /// send messages to.
+    ///
+    /// Services don't receive an `init` message, `make_service` creates the
+    /// service's state right away.
+    pub fn add_service(
+        &mut self,
+        node_id: NodeId,
+        make_service: impl FnOnce(MessageTransmitter<()>) -> Box<dyn NodeState>,
+    ) {
+        let (output_tx, output_rx) = mpsc::channel();
+        let _guard = clock::set_virtual_now(self.now);
+        let runtime = Runtime::started(
+            node_id,
+            OutputSender::unbounded(output_tx),
+            make_service,
+            self.error_policy,
+            Arc::new(Logger::from_env()),
+        );
+        self.insert_node(node_id, runtime, output_rx);
+    }
+
+    /// Adds in-process versions of Maelstrom's `seq-kv`, `lin-kv` and `lww-kv`.
+    pub fn add_kv_services(&mut self) {
+        for model in [
+            KvModel::Sequential,
+            KvModel::Linearizable,
+            KvModel::LastWriteWins,
+        ] {
+            let seed = self.rng.next_u64();
+            self.add_service(model.node_id(), |tx| {
+                Box::new(KvService::new(model, tx, seed))
+            });
+        }
+    }
+
+    fn insert_node(
+        &mut self,
+        node_id: NodeId,
+        runtime: Runtime,
+        output_rx: mpsc::Receiver<String>,
+    ) {
+        let next_wake_up = runtime.next_wake_up();
+        self.nodes.insert(
+            node_id,
+            SimNode {
+                runtime,
+                output_rx,
+                next_wake_up,
+            },
+        );
+    }
+
+    /// The identifiers of all simulated nodes (excluding services).
+    pub fn node_ids(&self) -> Vec<NodeId> {
+        self.node_ids.to_vec()
+    }
+
+    /// The metrics of a node or service.
+    pub fn metrics(&self, node_id: NodeId) -> Option<NodeMetrics> {
+        self.nodes.get(&node_id).map(|node| node.runtime.metrics())
+    }
+
+    /// The simulation's current (virtual) time.
+    pub fn now(&self) -> Instant {
+        self.now
+    }
+
+    /// Sends a message from client `src` to `dest`.
+    ///
+    /// `payload` has to serialize to a message body including its `type`.
+    pub fn send<P: Serialize>(&mut self, src: NodeId, dest: NodeId, payload: P) -> MessageId {
+        let msg_id = self.next_client_msg_id();
+        let message = Message {
+            header: MessageHeader {
+                src,
+                dest,
+                msg_id: Some(msg_id),
+                in_reply_to: None,
+            },
+            payload,
+        };
+        self.transmit(dest, serialize_message(&message));
+        msg_id
+    }
+
+    /// Like [Simulation::send], but without `msg_id` (see
+    /// [crate::MessageTransmitter::notify]).
+    pub fn notify<P: Serialize>(&mut self, src: NodeId, dest: NodeId, payload: P) {
+        let message = Message {
+            header: MessageHeader {
+                src,
+                dest,
+                msg_id: None,
+                in_reply_to: None,
+            },
+            payload,
+        };
+        self.transmit(dest, serialize_message(&message));
+    }
+
+    fn next_client_msg_id(&mut self) -> MessageId {
+        self.client_msg_ids
+            .next()
+            .map(MessageId::from)
+            .expect("exhausted available message ids")
+    }
+
+    /// Removes and returns all messages nodes sent to `client`.
+    pub fn take_client_messages<P: for<'a> Deserialize<'a>>(
+        &mut self,
+        client: NodeId,
+    ) -> Result<Vec<Message<P>>> {
+        let (taken, kept) = std::mem::take(&mut self.client_messages)
+            .into_iter()
+            .partition(|message| {
+                deserialize_header(message).is_ok_and(|header| header.dest == client)
+            });
+        self.client_messages = kept;
+        taken
+            .iter()
+            .map(|message: &String| deserialize_message(message))
+            .collect()
+    }
+
+    /// Processes events until no more messages are in flight.
+    ///
+    /// Timers only fire as long as there are messages in flight. Nodes that
+    /// keep sending from a periodic timer (e.g. to gossip) never become idle,
+    /// so this fails after [IDLE_TIME_LIMIT] of simulated time. Use
+    /// [Simulation::run_for] for such nodes.
+    pub fn run_until_idle(&mut self) -> Result<()> {
+        let deadline = self.now + IDLE_TIME_LIMIT;
+        while !self.in_flight.is_empty() {
+            if !self.step(Some(deadline))? {
+                bail!(
+                    "nodes are still sending after {IDLE_TIME_LIMIT:?} of simulated time, \
+                     use run_for instead"
+                );
+            }
+        }
+        Ok(())
+    }
+
+    /// Processes all events up to `deadline` and advances the clock to it.
+    pub fn run_until(&mut self, deadline: Instant) -> Result<()> {
+        while self.step(Some(deadline))? {}
+        self.now = self.now.max(deadline);
+        Ok(())
+    }
+
+    /// Like [Simulation::run_until], relative to [Simulation::now].
+    pub fn run_for(&mut self, duration: Duration) -> Result<()> {
+        self.run_until(self.now + duration)
+    }
+
+    /// Calls [NodeState::on_shutdown] on all nodes and delivers the messages
+    /// they send, like [crate::run_node] does when STDIN is closed.
+    pub fn shutdown(&mut self) -> Result<()> {
+        let _guard = clock::set_virtual_now(self.now);
+        let node_ids: Vec<NodeId> = self.nodes.keys().copied().collect();
+        for node_id in node_ids {
+            let node = self.nodes.get_mut(&node_id).expect("node should exist");
+            node.runtime.shutdown();
+            self.collect_output(node_id)?;
+        }
+        self.run_until_idle()
+    }
+
+    /// Processes the next event (if it's not after `deadline`).
+    ///
+    /// Returns whether an event was processed.
+    fn step(&mut self, deadline: Option<Instant>) -> Result<bool> {
+        let next_message = self.in_flight.peek().map(|Reverse(m)| m.deliver_at);
+        let next_wake_up = self
+            .nodes
+            .iter()
+            .filter_map(|(&node_id, node)| node.next_wake_up.map(|instant| (instant, node_id)))
+            .min();
+
+        let (at, event) = match (next_message, next_wake_up) {
+            (None, None) => return Ok(false),
+            (Some(at), Some((wake_up_at, node_id))) if wake_up_at < at => {
+                (wake_up_at, Event::WakeUp(node_id))
+            }
+            (None, Some((wake_up_at, node_id))) => (wake_up_at, Event::WakeUp(node_id)),
+            (Some(at), _) => (at, Event::Deliver),
+        };
+        if deadline.is_some_and(|deadline| at > deadline) {
+            return Ok(false);
+        }
+        self.now = self.now.max(at);
+        let _guard = clock::set_virtual_now(self.now);
+
+        match event {
+            Event::Deliver => {
+                let Reverse(InFlight { dest, message, .. }) =
+                    self.in_flight.pop().expect("a message should be in flight");
+                self.deliver(dest, &message)?;
+            }
+            Event::WakeUp(node_id) => {
+                let node = self.nodes.get_mut(&node_id).expect("node should exist");
+                node.runtime
+                    .wake_up()
+                    .with_context(|| format!("node {node_id} failed to wake up"))?;
+                self.collect_output(node_id)?;
+            }
+        };
+        Ok(true)
+    }
+
+    fn deliver(&mut self, dest: NodeId, message: &str) -> Result<()> {
+        let node = self.nodes.get_mut(&dest).expect("dest should be a node");
+        node.runtime
+            .handle_message(message)
+            .with_context(|| format!("node {dest} failed to handle {message}"))?;
+        self.collect_output(dest)
+    }
+
+    /// Puts everything a node sent on the wire and updates its wake up time.
+    fn collect_output(&mut self, node_id: NodeId) -> Result<()> {
+        let node = self.nodes.get_mut(&node_id).expect("node should exist");
+        node.next_wake_up = node.runtime.next_wake_up();
+        let messages: Vec<String> = node.output_rx.try_iter().collect();
+        for message in messages {
+            let header = deserialize_header(&message)
+                .map_err(|err| anyhow!("node {node_id} sent an invalid message: {err}"))?;
+            self.transmit(header.dest, message);
+        }
+        Ok(())
+    }
+
+    fn transmit(&mut self, dest: NodeId, message: String) {
+        if self.nodes.contains_key(&dest) {
+            let deliver_at = self.now + self.latency.sample(&mut self.rng);
+            let seq = self.next_seq;
+            self.next_seq += 1;
+            self.in_flight.push(Reverse(InFlight {
+                deliver_at,
+                seq,
+                dest,
+                message,
+            }));
+        } else {
+            self.client_messages.push(message);
+        }
+    }
+}
+
+enum Event {
+    Deliver,
+    WakeUp(NodeId),
+}
+
+#[derive(Clone, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum InitRequest {
+    Init {
+        node_id: NodeId,
+        node_ids: Box<[NodeId]>,
+    },
+}
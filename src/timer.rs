@@ -0,0 +1,164 @@
+use std::{
+    collections::{BTreeSet, HashMap},
+    sync::{Arc, Mutex, MutexGuard},
+    time::{Duration, Instant},
+};
+
+use crate::now;
+
+/// A timer's unique identifier within a node.
+///
+/// Identifiers are created by [Timers] and passed to [crate::NodeState::wake_up]
+/// when the timer expires.
+#[derive(PartialOrd, Ord, PartialEq, Eq, Clone, Copy, Hash, Debug)]
+pub struct TimerId(u64);
+
+impl TimerId {
+    /// The timer requested via [crate::NodeState::next_wake_up].
+    pub const NEXT_WAKE_UP: TimerId = TimerId(0);
+}
+
+/// Schedules and cancels a node's timers.
+///
+/// All clones refer to the same set of timers. Get one via
+/// [crate::MessageTransmitter::timers].
+#[derive(Clone, Debug, Default)]
+pub struct Timers {
+    inner: Arc<Mutex<TimerQueue>>,
+}
+
+impl Timers {
+    /// Schedules a timer expiring at `instant`.
+    pub fn schedule_at(&self, instant: Instant) -> TimerId {
+        self.lock().insert(instant, None)
+    }
+
+    /// Schedules a timer expiring after `delay`.
+    pub fn schedule_in(&self, delay: Duration) -> TimerId {
+        self.schedule_at(now() + delay)
+    }
+
+    /// Schedules a timer expiring every `period` until it gets cancelled.
+    pub fn schedule_every(&self, period: Duration) -> TimerId {
+        assert!(!period.is_zero(), "period must not be zero");
+        self.lock().insert(now() + period, Some(period))
+    }
+
+    /// Cancels a timer.
+    ///
+    /// Returns `false` if the timer already expired (or was cancelled).
+    pub fn cancel(&self, id: TimerId) -> bool {
+        self.lock().remove(id)
+    }
+
+    /// Removes all expired timers (rescheduling periodic ones).
+    pub(crate) fn expire(&self, now: Instant) -> Vec<TimerId> {
+        self.lock().expire(now)
+    }
+
+    pub(crate) fn next_deadline(&self) -> Option<Instant> {
+        self.lock().next_deadline()
+    }
+
+    fn lock(&self) -> MutexGuard<'_, TimerQueue> {
+        self.inner.lock().expect("lock should not be poisoned")
+    }
+}
+
+#[derive(Debug)]
+struct TimerQueue {
+    next_id: u64,
+    timers: HashMap<TimerId, TimerEntry>,
+    deadlines: BTreeSet<(Instant, TimerId)>,
+}
+
+#[derive(Debug)]
+struct TimerEntry {
+    deadline: Instant,
+    period: Option<Duration>,
+}
+
+impl Default for TimerQueue {
+    fn default() -> Self {
+        Self {
+            // 0 is reserved for `TimerId::NEXT_WAKE_UP`.
+            next_id: 1,
+            timers: HashMap::new(),
+            deadlines: BTreeSet::new(),
+        }
+    }
+}
+
+impl TimerQueue {
+    fn insert(&mut self, deadline: Instant, period: Option<Duration>) -> TimerId {
+        let id = TimerId(self.next_id);
+        self.next_id += 1;
+        self.timers.insert(id, TimerEntry { deadline, period });
+        self.deadlines.insert((deadline, id));
+        id
+    }
+
+    fn remove(&mut self, id: TimerId) -> bool {
+        if let Some(entry) = self.timers.remove(&id) {
+            self.deadlines.remove(&(entry.deadline, id));
+            true
+        } else {
+            false
+        }
+    }
+
+    fn expire(&mut self, now: Instant) -> Vec<TimerId> {
+        let mut expired = Vec::new();
+        while let Some(&(deadline, id)) = self.deadlines.first() {
+            if deadline > now {
+                break;
+            }
+            self.deadlines.pop_first();
+            expired.push(id);
+
+            let entry = self.timers.get_mut(&id).expect("timer should exist");
+            if let Some(period) = entry.period {
+                // Skip missed periods instead of firing repeatedly to catch up.
+                let mut next_deadline = deadline + period;
+                if next_deadline <= now {
+                    next_deadline = now + period;
+                }
+                entry.deadline = next_deadline;
+                self.deadlines.insert((next_deadline, id));
+            } else {
+                self.timers.remove(&id);
+            }
+        }
+        expired
+    }
+
+    fn next_deadline(&self) -> Option<Instant> {
+        self.deadlines.first().map(|(deadline, _)| *deadline)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn expire_one_shot_and_periodic_timers() {
+        let timers = Timers::default();
+        let start = Instant::now();
+        let one_shot = timers.schedule_at(start);
+        let cancelled = timers.schedule_at(start);
+        let periodic = timers.schedule_every(Duration::from_millis(100));
+        assert!(timers.cancel(cancelled));
+        assert!(!timers.cancel(cancelled));
+
+        assert_eq!(timers.expire(start), vec![one_shot]);
+        assert_eq!(
+            timers.expire(start + Duration::from_millis(150)),
+            vec![periodic]
+        );
+        // The periodic timer stays scheduled.
+        assert!(timers.next_deadline().is_some());
+        assert!(timers.cancel(periodic));
+        assert_eq!(timers.next_deadline(), None);
+    }
+}
@@ -0,0 +1,313 @@
+//! Graphs describing which nodes talk to each other, see [Topology].
+//!
+//! Maelstrom sends a `topology` message with a suggested graph, which
+//! deserializes into a [Topology]. Alternatively, overlays can be generated
+//! from the `node_ids` of the `init` message, e.g. to trade the number of
+//! messages for latency when gossiping:
+//!
+//! ```
+//! # use fly_into_the_maelstrom::{topology::Topology, NodeId};
+//! let node_ids: Vec<NodeId> = (0..25).map(|i| format!("n{i}").parse().unwrap()).collect();
+//! let tree = Topology::tree(&node_ids, 4);
+//! assert_eq!(tree.edge_count(), 24);
+//! assert_eq!(tree.diameter(), Some(5));
+//! let grid = Topology::grid(&node_ids);
+//! assert_eq!(grid.diameter(), Some(8));
+//! ```
+
+use std::collections::{btree_map::Entry, BTreeMap, BTreeSet, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{rng::Rng, NodeId};
+
+/// An undirected graph of nodes.
+///
+/// Serializes like the `topology` of Maelstrom's `topology` message, i.e. as
+/// a map from each node to its neighbors. Edges are always added in both
+/// directions, even if the map only lists one.
+#[derive(PartialEq, Eq, Clone, Debug, Default, Serialize, Deserialize)]
+#[serde(
+    from = "BTreeMap<NodeId, Vec<NodeId>>",
+    into = "BTreeMap<NodeId, Vec<NodeId>>"
+)]
+pub struct Topology {
+    neighbors: BTreeMap<NodeId, BTreeSet<NodeId>>,
+}
+
+impl Topology {
+    /// Creates a graph of `node_ids` without any edges.
+    pub fn new(node_ids: &[NodeId]) -> Self {
+        Self {
+            neighbors: node_ids.iter().map(|&n| (n, BTreeSet::new())).collect(),
+        }
+    }
+
+    /// Every node is connected to every other node.
+    pub fn complete(node_ids: &[NodeId]) -> Self {
+        let mut topology = Self::new(node_ids);
+        for (i, &a) in node_ids.iter().enumerate() {
+            for &b in &node_ids[i + 1..] {
+                topology.add_edge(a, b);
+            }
+        }
+        topology
+    }
+
+    /// Connects the nodes in a circle, in the order given.
+    pub fn ring(node_ids: &[NodeId]) -> Self {
+        let mut topology = Self::new(node_ids);
+        if node_ids.len() > 1 {
+            for (i, &a) in node_ids.iter().enumerate() {
+                topology.add_edge(a, node_ids[(i + 1) % node_ids.len()]);
+            }
+        }
+        topology
+    }
+
+    /// A tree in which every node has up to `k` children.
+    ///
+    /// The first node is the root, followed by its children, their children
+    /// and so on.
+    pub fn tree(node_ids: &[NodeId], k: usize) -> Self {
+        assert!(k > 0, "a tree needs at least one child per node");
+        let mut topology = Self::new(node_ids);
+        for (i, &node_id) in node_ids.iter().enumerate().skip(1) {
+            topology.add_edge(node_ids[(i - 1) / k], node_id);
+        }
+        topology
+    }
+
+    /// Arranges the nodes row by row in a square grid and connects each one
+    /// to the nodes next to, above and below it.
+    ///
+    /// The last row may be incomplete.
+    pub fn grid(node_ids: &[NodeId]) -> Self {
+        let width = (1..).find(|w| w * w >= node_ids.len()).unwrap_or(1);
+        let mut topology = Self::new(node_ids);
+        for (i, &node_id) in node_ids.iter().enumerate() {
+            if (i + 1) % width != 0 {
+                if let Some(&right) = node_ids.get(i + 1) {
+                    topology.add_edge(node_id, right);
+                }
+            }
+            if let Some(&below) = node_ids.get(i + width) {
+                topology.add_edge(node_id, below);
+            }
+        }
+        topology
+    }
+
+    /// A random graph in which the nodes have `degree` neighbors where
+    /// possible.
+    ///
+    /// It always contains a ring through all nodes (in random order), so it
+    /// is connected and nodes have at least two neighbors. The same `seed`
+    /// produces the same graph on every node.
+    pub fn random_regular(node_ids: &[NodeId], degree: usize, seed: u64) -> Self {
+        let mut rng = Rng::new(seed);
+        let mut order = node_ids.to_vec();
+        for i in (1..order.len()).rev() {
+            order.swap(i, rng.below(i as u64 + 1) as usize);
+        }
+        let mut topology = Self::ring(&order);
+        let degree = degree.min(node_ids.len().saturating_sub(1));
+        for _ in 0..node_ids.len() * degree * 10 {
+            let short: Vec<NodeId> = order
+                .iter()
+                .copied()
+                .filter(|&n| topology.degree(n) < degree)
+                .collect();
+            if short.len() < 2 {
+                break;
+            }
+            let a = short[rng.below(short.len() as u64) as usize];
+            let b = short[rng.below(short.len() as u64) as usize];
+            if a != b {
+                topology.add_edge(a, b);
+            }
+        }
+        topology
+    }
+
+    /// Connects `a` and `b`, adding them if necessary.
+    ///
+    /// Edges from a node to itself are ignored.
+    pub fn add_edge(&mut self, a: NodeId, b: NodeId) {
+        self.neighbors.entry(a).or_default();
+        self.neighbors.entry(b).or_default();
+        if a != b {
+            self.neighbors.get_mut(&a).unwrap().insert(b);
+            self.neighbors.get_mut(&b).unwrap().insert(a);
+        }
+    }
+
+    pub fn contains_edge(&self, a: NodeId, b: NodeId) -> bool {
+        self.neighbors.get(&a).is_some_and(|n| n.contains(&b))
+    }
+
+    /// All nodes in ascending order.
+    pub fn node_ids(&self) -> impl Iterator<Item = NodeId> + '_ {
+        self.neighbors.keys().copied()
+    }
+
+    /// The neighbors of `node_id` in ascending order, none if it is unknown.
+    pub fn neighbors(&self, node_id: NodeId) -> impl Iterator<Item = NodeId> + '_ {
+        self.neighbors.get(&node_id).into_iter().flatten().copied()
+    }
+
+    /// The number of neighbors of `node_id`.
+    pub fn degree(&self, node_id: NodeId) -> usize {
+        self.neighbors.get(&node_id).map_or(0, BTreeSet::len)
+    }
+
+    pub fn edge_count(&self) -> usize {
+        self.neighbors.values().map(BTreeSet::len).sum::<usize>() / 2
+    }
+
+    /// The number of hops from `from` to every node reachable from it.
+    pub fn distances(&self, from: NodeId) -> BTreeMap<NodeId, usize> {
+        let mut distances = BTreeMap::new();
+        if !self.neighbors.contains_key(&from) {
+            return distances;
+        }
+        distances.insert(from, 0);
+        let mut queue = VecDeque::from([from]);
+        while let Some(node_id) = queue.pop_front() {
+            let distance = distances[&node_id] + 1;
+            for neighbor in self.neighbors(node_id) {
+                if let Entry::Vacant(entry) = distances.entry(neighbor) {
+                    entry.insert(distance);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        distances
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.neighbors
+            .keys()
+            .next()
+            .is_none_or(|&n| self.distances(n).len() == self.neighbors.len())
+    }
+
+    /// The largest number of hops between any two nodes, i.e. how many hops
+    /// gossip takes at most to reach every node.
+    ///
+    /// Returns `None` if the graph is empty or not connected.
+    pub fn diameter(&self) -> Option<usize> {
+        let mut diameter = None;
+        for &node_id in self.neighbors.keys() {
+            let distances = self.distances(node_id);
+            if distances.len() < self.neighbors.len() {
+                return None;
+            }
+            diameter = diameter.max(distances.into_values().max());
+        }
+        diameter
+    }
+
+    /// A tree containing the shortest paths from `root` to all nodes
+    /// reachable from it (found by breadth-first search).
+    ///
+    /// Unreachable nodes are left out.
+    pub fn spanning_tree(&self, root: NodeId) -> Self {
+        let mut tree = Self::new(&[]);
+        if !self.neighbors.contains_key(&root) {
+            return tree;
+        }
+        tree.neighbors.insert(root, BTreeSet::new());
+        let mut queue = VecDeque::from([root]);
+        while let Some(node_id) = queue.pop_front() {
+            for neighbor in self.neighbors(node_id) {
+                if !tree.neighbors.contains_key(&neighbor) {
+                    tree.add_edge(node_id, neighbor);
+                    queue.push_back(neighbor);
+                }
+            }
+        }
+        tree
+    }
+}
+
+impl From<BTreeMap<NodeId, Vec<NodeId>>> for Topology {
+    fn from(map: BTreeMap<NodeId, Vec<NodeId>>) -> Self {
+        let mut topology = Self::new(&[]);
+        for (node_id, neighbors) in map {
+            topology.neighbors.entry(node_id).or_default();
+            for neighbor in neighbors {
+                topology.add_edge(node_id, neighbor);
+            }
+        }
+        topology
+    }
+}
+
+impl From<Topology> for BTreeMap<NodeId, Vec<NodeId>> {
+    fn from(topology: Topology) -> Self {
+        topology
+            .neighbors
+            .into_iter()
+            .map(|(node_id, neighbors)| (node_id, neighbors.into_iter().collect()))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn node_ids(n: usize) -> Vec<NodeId> {
+        (0..n).map(|i| format!("n{i}").parse().unwrap()).collect()
+    }
+
+    #[test]
+    fn parses_maelstrom_topology() {
+        let topology: Topology =
+            serde_json::from_str(r#"{"n0":["n1"],"n1":["n2"],"n2":[],"n3":[]}"#).unwrap();
+        let [n0, n1, n2, n3] = node_ids(4).try_into().unwrap();
+        assert!(topology.contains_edge(n1, n0));
+        assert_eq!(topology.neighbors(n1).collect::<Vec<_>>(), [n0, n2]);
+        assert_eq!(topology.degree(n3), 0);
+        assert_eq!(topology.distances(n0)[&n2], 2);
+        assert!(!topology.is_connected());
+        assert_eq!(topology.diameter(), None);
+    }
+
+    #[test]
+    fn generated_overlays() {
+        let node_ids = node_ids(10);
+        let ring = Topology::ring(&node_ids);
+        assert_eq!(ring.edge_count(), 10);
+        assert_eq!(ring.diameter(), Some(5));
+
+        let tree = Topology::tree(&node_ids, 3);
+        assert_eq!(tree.edge_count(), 9);
+        assert_eq!(tree.degree(node_ids[0]), 3);
+        assert_eq!(tree.diameter(), Some(4));
+
+        // Three rows of four nodes, the last one with only two.
+        let grid = Topology::grid(&node_ids);
+        assert_eq!(grid.degree(node_ids[5]), 4);
+        assert_eq!(grid.diameter(), Some(5));
+
+        let complete = Topology::complete(&node_ids);
+        assert_eq!(complete.edge_count(), 45);
+        assert_eq!(complete.diameter(), Some(1));
+        assert_eq!(
+            complete.spanning_tree(node_ids[0]),
+            Topology::tree(&node_ids, 9)
+        );
+    }
+
+    #[test]
+    fn random_regular_is_connected_and_reproducible() {
+        let node_ids = node_ids(25);
+        let graph = Topology::random_regular(&node_ids, 4, 7);
+        assert!(graph.is_connected());
+        assert!(node_ids.iter().all(|&n| graph.degree(n) >= 2));
+        assert!(node_ids.iter().filter(|&&n| graph.degree(n) == 4).count() >= 23);
+        assert_eq!(graph, Topology::random_regular(&node_ids, 4, 7));
+    }
+}
@@ -0,0 +1,238 @@
+//! Recording and replaying what a node did.
+//!
+//! With [crate::NodeOptions::trace] (or the environment variable
+//! `MAELSTROM_TRACE_DIR`), [crate::run_node] writes every input line, wake up
+//! and output line to a JSONL file, one [TraceRecord] per line. [replay] feeds
+//! such a trace into a fresh node and compares what it sends with what was
+//! recorded. This reproduces a node's behavior in a Maelstrom run locally, e.g.
+//! under a debugger:
+//!
+//! ```no_run
+//! # use fly_into_the_maelstrom::{trace::*, *};
+//! # fn make_node(_: InitPayload, _: MessageTransmitter<()>) -> anyhow::Result<Box<dyn NodeState>> { todo!() }
+//! let report = replay_file(
+//!     "traces/12345.jsonl",
+//!     &NodeOptions::default(),
+//!     Box::new(make_node),
+//! )
+//! .unwrap();
+//! assert!(report.is_match(), "{report}");
+//! ```
+//!
+//! During a traced run, [crate::now] returns the time the current input was
+//! recorded at (like in a [crate::sim::Simulation]), so replays see the same
+//! times.
+
+use std::{
+    fmt,
+    fs::File,
+    io::{BufRead, BufReader, BufWriter, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc},
+    time::{Duration, Instant},
+};
+
+use anyhow::{Context, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::{
+    clock::{self, VirtualNowGuard},
+    input::NodeInput,
+    output::{Channel, OutputSender},
+    runtime::Runtime,
+    AfterInitTransition, Logger, NodeOptions,
+};
+
+/// One line of a trace.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+pub struct TraceRecord {
+    /// Microseconds since the node started.
+    pub time_us: u64,
+    #[serde(flatten)]
+    pub event: TraceEvent,
+}
+
+/// Something that happened to a node.
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "event", content = "line", rename_all = "snake_case")]
+pub enum TraceEvent {
+    /// A line read from STDIN.
+    Input(String),
+    /// A wake up call (see [crate::NodeState::next_wake_up]).
+    WakeUp,
+    /// STDIN was closed or the process received a signal.
+    Shutdown,
+    /// A line the node wrote to STDOUT.
+    Output(String),
+}
+
+/// Returns `$MAELSTROM_TRACE_DIR/<pid>.jsonl` if the variable is set.
+pub(crate) fn path_from_env() -> Option<PathBuf> {
+    let dir = std::env::var_os("MAELSTROM_TRACE_DIR")?;
+    Some(Path::new(&dir).join(format!("{}.jsonl", std::process::id())))
+}
+
+/// Records a node's inputs and outputs while [crate::run_node] runs it.
+///
+/// The node sends its output here first, so every output line is recorded
+/// right after the input that caused it.
+pub(crate) struct Tracer {
+    start: Instant,
+    file: BufWriter<File>,
+    output_rx: mpsc::Receiver<String>,
+    stdout: Channel,
+}
+
+impl Tracer {
+    /// Creates the trace file and returns the sender for the node's output.
+    pub(crate) fn create(path: &Path, stdout_tx: OutputSender) -> Result<(OutputSender, Self)> {
+        let file = File::create(path)
+            .with_context(|| format!("failed to create trace {}", path.display()))?;
+        let (output_tx, output_rx) = mpsc::channel();
+        let (output_tx, stdout) = stdout_tx.redirect(output_tx);
+        let tracer = Self {
+            start: Instant::now(),
+            file: BufWriter::new(file),
+            output_rx,
+            stdout,
+        };
+        Ok((output_tx, tracer))
+    }
+
+    /// Records `input` and fixes [crate::now] to the time it was recorded at
+    /// until the guard is dropped.
+    pub(crate) fn record_input(&mut self, input: &NodeInput) -> Result<VirtualNowGuard> {
+        let now = Instant::now();
+        let event = match input {
+            NodeInput::Message(line) => TraceEvent::Input(line.clone()),
+            NodeInput::WakeUp => TraceEvent::WakeUp,
+            NodeInput::Shutdown => TraceEvent::Shutdown,
+        };
+        self.record(now, event)?;
+        Ok(clock::set_virtual_now(now))
+    }
+
+    /// Records everything the node sent since the last call and passes it on
+    /// to STDOUT.
+    pub(crate) fn forward_output(&mut self) -> Result<()> {
+        let now = Instant::now();
+        while let Ok(line) = self.output_rx.try_recv() {
+            self.record(now, TraceEvent::Output(line.clone()))?;
+            self.stdout.send(line);
+        }
+        // The trace is most useful when the node crashes, so don't keep
+        // anything in the buffer.
+        self.file.flush()?;
+        Ok(())
+    }
+
+    fn record(&mut self, now: Instant, event: TraceEvent) -> Result<()> {
+        let record = TraceRecord {
+            time_us: (now - self.start).as_micros() as u64,
+            event,
+        };
+        serde_json::to_writer(&mut self.file, &record)?;
+        self.file.write_all(b"\n")?;
+        Ok(())
+    }
+}
+
+/// The outcome of [replay].
+#[derive(PartialEq, Eq, Clone, Debug)]
+pub struct ReplayReport {
+    /// The recorded output lines.
+    pub expected: Vec<String>,
+    /// The output lines of the replayed node.
+    pub actual: Vec<String>,
+}
+
+impl ReplayReport {
+    /// Whether the replayed node sent what was recorded.
+    ///
+    /// Lines are compared as JSON, so e.g. the order of fields doesn't matter.
+    pub fn is_match(&self) -> bool {
+        self.first_mismatch().is_none()
+    }
+
+    /// The index of the first output line that differs (or is missing).
+    pub fn first_mismatch(&self) -> Option<usize> {
+        let len = self.expected.len().max(self.actual.len());
+        (0..len).find(|&i| match (self.expected.get(i), self.actual.get(i)) {
+            (Some(expected), Some(actual)) => !same_json(expected, actual),
+            _ => true,
+        })
+    }
+}
+
+fn same_json(a: &str, b: &str) -> bool {
+    let parse = |s| serde_json::from_str::<serde_json::Value>(s).ok();
+    match (parse(a), parse(b)) {
+        (Some(a), Some(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+impl fmt::Display for ReplayReport {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let Some(i) = self.first_mismatch() else {
+            return write!(f, "all {} output lines match", self.expected.len());
+        };
+        let line = |lines: &[String]| lines.get(i).map_or("<none>".to_owned(), Clone::clone);
+        writeln!(f, "output line {i} differs:")?;
+        writeln!(f, "  recorded: {}", line(&self.expected))?;
+        write!(f, "  replayed: {}", line(&self.actual))
+    }
+}
+
+/// Runs a fresh node through the inputs of a recorded trace.
+///
+/// Inputs are handled at their recorded (virtual) time. Fails if the trace
+/// can't be read or the node fails like it would in [crate::run_node].
+pub fn replay(
+    trace: impl BufRead,
+    options: &NodeOptions,
+    after_init: AfterInitTransition,
+) -> Result<ReplayReport> {
+    let start = Instant::now();
+    let (output_tx, output_rx) = mpsc::channel();
+    let mut runtime = Runtime::new(
+        OutputSender::unbounded(output_tx),
+        after_init,
+        options.error_policy,
+        Arc::new(Logger::from_env()),
+    );
+    if options.answer_metrics_requests {
+        runtime.answer_metrics_requests();
+    }
+    let mut report = ReplayReport {
+        expected: Vec::new(),
+        actual: Vec::new(),
+    };
+    for (i, line) in trace.lines().enumerate() {
+        let record: TraceRecord = serde_json::from_str(&line?)
+            .with_context(|| format!("invalid record in line {}", i + 1))?;
+        let _guard = clock::set_virtual_now(start + Duration::from_micros(record.time_us));
+        match record.event {
+            TraceEvent::Input(message) => runtime
+                .handle_message(&message)
+                .with_context(|| format!("node failed to handle {message}"))?,
+            TraceEvent::WakeUp => runtime.wake_up().context("node failed to wake up")?,
+            TraceEvent::Shutdown => runtime.shutdown(),
+            TraceEvent::Output(message) => report.expected.push(message),
+        }
+        report.actual.extend(output_rx.try_iter());
+    }
+    Ok(report)
+}
+
+/// Like [replay], for a trace file.
+pub fn replay_file(
+    path: impl AsRef<Path>,
+    options: &NodeOptions,
+    after_init: AfterInitTransition,
+) -> Result<ReplayReport> {
+    let path = path.as_ref();
+    let file =
+        File::open(path).with_context(|| format!("failed to open trace {}", path.display()))?;
+    replay(BufReader::new(file), options, after_init)
+}
@@ -0,0 +1,193 @@
+//! Where a node reads its messages from and writes them to.
+//!
+//! [crate::run_node] talks to Maelstrom via STDIN and STDOUT ([Stdio]).
+//! [crate::run_node_on] runs the same node on any other [Transport], e.g. a
+//! socket or an in-memory [MemoryTransport]:
+//!
+//! ```no_run
+//! # use fly_into_the_maelstrom::*;
+//! # fn make_node(_: InitPayload, _: MessageTransmitter<()>) -> anyhow::Result<Box<dyn NodeState>> { todo!() }
+//! let stream = std::net::TcpStream::connect("127.0.0.1:4000").unwrap();
+//! run_node_on(stream, NodeOptions::default(), Box::new(make_node)).unwrap();
+//! ```
+
+use std::{
+    io::{self, BufRead, BufReader, Read, Write},
+    net::TcpStream,
+    sync::mpsc,
+};
+
+/// A connection carrying one message per line in each direction.
+///
+/// The node shuts down once the reader reaches its end or writing fails.
+pub trait Transport {
+    type Reader: BufRead + Send + 'static;
+    type Writer: Write + Send + 'static;
+
+    /// Splits the connection into its incoming and outgoing half.
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)>;
+}
+
+/// STDIN and STDOUT, as used by Maelstrom.
+#[derive(Clone, Copy, Debug, Default)]
+pub struct Stdio;
+
+impl Transport for Stdio {
+    type Reader = BufReader<io::Stdin>;
+    type Writer = io::Stdout;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((BufReader::new(io::stdin()), io::stdout()))
+    }
+}
+
+impl Transport for TcpStream {
+    type Reader = BufReader<TcpStream>;
+    type Writer = TcpStream;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((BufReader::new(self.try_clone()?), self))
+    }
+}
+
+#[cfg(unix)]
+impl Transport for std::os::unix::net::UnixStream {
+    type Reader = BufReader<Self>;
+    type Writer = Self;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((BufReader::new(self.try_clone()?), self))
+    }
+}
+
+/// One end of an in-process connection, see [MemoryTransport::pair].
+#[derive(Debug)]
+pub struct MemoryTransport {
+    reader: PipeReader,
+    writer: PipeWriter,
+}
+
+impl MemoryTransport {
+    /// Creates two connected ends: what is written to one can be read from
+    /// the other.
+    ///
+    /// Run a node on one end and use the other (after [Transport::split]) to
+    /// talk to it, e.g. in tests.
+    pub fn pair() -> (Self, Self) {
+        let (a_writer, b_reader) = pipe();
+        let (b_writer, a_reader) = pipe();
+        (
+            Self {
+                reader: a_reader,
+                writer: a_writer,
+            },
+            Self {
+                reader: b_reader,
+                writer: b_writer,
+            },
+        )
+    }
+}
+
+impl Transport for MemoryTransport {
+    type Reader = PipeReader;
+    type Writer = PipeWriter;
+
+    fn split(self) -> io::Result<(Self::Reader, Self::Writer)> {
+        Ok((self.reader, self.writer))
+    }
+}
+
+/// Creates an in-memory pipe.
+///
+/// The reader reaches its end once the writer was dropped. Writing fails with
+/// [io::ErrorKind::BrokenPipe] once the reader was dropped.
+pub fn pipe() -> (PipeWriter, PipeReader) {
+    let (tx, rx) = mpsc::channel();
+    (
+        PipeWriter(tx),
+        PipeReader {
+            rx,
+            chunk: Vec::new(),
+            pos: 0,
+        },
+    )
+}
+
+/// The writing half of a [pipe].
+#[derive(Clone, Debug)]
+pub struct PipeWriter(mpsc::Sender<Vec<u8>>);
+
+impl Write for PipeWriter {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0
+            .send(buf.to_vec())
+            .map_err(|_| io::ErrorKind::BrokenPipe)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+/// The reading half of a [pipe].
+#[derive(Debug)]
+pub struct PipeReader {
+    rx: mpsc::Receiver<Vec<u8>>,
+    chunk: Vec<u8>,
+    pos: usize,
+}
+
+impl Read for PipeReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let available = self.fill_buf()?;
+        let n = available.len().min(buf.len());
+        buf[..n].copy_from_slice(&available[..n]);
+        self.consume(n);
+        Ok(n)
+    }
+}
+
+impl BufRead for PipeReader {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        while self.pos == self.chunk.len() {
+            match self.rx.recv() {
+                Ok(chunk) => {
+                    self.chunk = chunk;
+                    self.pos = 0;
+                }
+                // All writers are gone.
+                Err(_) => break,
+            }
+        }
+        Ok(&self.chunk[self.pos..])
+    }
+
+    fn consume(&mut self, amt: usize) {
+        self.pos = (self.pos + amt).min(self.chunk.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pipe_transfers_lines() {
+        let (mut writer, reader) = pipe();
+        writer.write_all(b"first\nsec").unwrap();
+        writer.write_all(b"ond\n").unwrap();
+        drop(writer);
+        let lines: Vec<_> = reader.lines().map(Result::unwrap).collect();
+        assert_eq!(lines, ["first", "second"]);
+    }
+
+    #[test]
+    fn writing_to_closed_pipe_fails() {
+        let (mut writer, reader) = pipe();
+        drop(reader);
+        let error = writer.write_all(b"hello\n").unwrap_err();
+        assert_eq!(error.kind(), io::ErrorKind::BrokenPipe);
+    }
+}
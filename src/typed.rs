@@ -0,0 +1,158 @@
+use std::{fmt, iter, time::Instant};
+
+use serde::{
+    de::{self, value::MapDeserializer, DeserializeOwned},
+    Serialize,
+};
+
+use crate::{
+    Envelope, ErrorCode, Message, MessageTransmitter, NodeState, RequestHeader, RpcResponse,
+    TimerId, Transition,
+};
+
+/// A [NodeState] that receives deserialized messages.
+///
+/// Every `TypedNodeState` is a [NodeState]: incoming messages are deserialized
+/// into [Message]s of [TypedNodeState::Request] before they are passed to
+/// [TypedNodeState::handle_request]. Requests that don't deserialize are
+/// answered with [ErrorCode::NotSupported] (for unknown `type`s) or
+/// [ErrorCode::MalformedRequest] instead of failing the node. Such replies
+/// are sent with [TypedNodeState::transmitter].
+///
+/// Replies to other messages (e.g. to RPCs that already timed out) are
+/// dropped if they don't deserialize, as answering them with an error would
+/// make no sense.
+pub trait TypedNodeState {
+    /// The payload of incoming messages.
+    type Request: DeserializeOwned;
+
+    /// The payload of outgoing messages.
+    type Response: Serialize;
+
+    /// Returns the transmitter used for replying to invalid requests.
+    fn transmitter(&mut self) -> &mut MessageTransmitter<Self::Response>;
+
+    /// Handles an incoming message.
+    ///
+    /// See [NodeState::handle].
+    fn handle_request(&mut self, request: Message<Self::Request>) -> Transition;
+
+    /// See [NodeState::wake_up].
+    fn wake_up(&mut self, timer: TimerId) -> Transition {
+        let _ = timer;
+        Ok(None)
+    }
+
+    /// See [NodeState::rpc_reply].
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        let _ = response;
+        Ok(None)
+    }
+
+    /// See [NodeState::next_wake_up].
+    fn next_wake_up(&self) -> Option<Instant> {
+        None
+    }
+
+    /// See [NodeState::on_shutdown].
+    fn on_shutdown(&mut self) {}
+}
+
+impl<T: TypedNodeState + 'static> NodeState for T {
+    fn handle(&mut self, request: &str) -> Transition {
+        self.handle_envelope(Envelope::parse(request)?)
+    }
+
+    fn handle_envelope(&mut self, request: Envelope<'_>) -> Transition {
+        match parse_request(&request) {
+            ParsedRequest::Valid(request) => self.handle_request(request),
+            ParsedRequest::Ignored => Ok(None),
+            ParsedRequest::Invalid(header, code, text) => {
+                self.transmitter().reply_error(&header, code, text);
+                Ok(None)
+            }
+        }
+    }
+
+    fn wake_up(&mut self, timer: TimerId) -> Transition {
+        TypedNodeState::wake_up(self, timer)
+    }
+
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        TypedNodeState::rpc_reply(self, response)
+    }
+
+    fn next_wake_up(&self) -> Option<Instant> {
+        TypedNodeState::next_wake_up(self)
+    }
+
+    fn on_shutdown(&mut self) {
+        TypedNodeState::on_shutdown(self)
+    }
+}
+
+/// The outcome of [parse_request].
+pub(crate) enum ParsedRequest<R> {
+    Valid(Message<R>),
+    /// An invalid message we can't or shouldn't reply to.
+    Ignored,
+    /// An invalid request that should be answered with an error.
+    Invalid(RequestHeader, ErrorCode, String),
+}
+
+/// Deserializes a request for a typed handler.
+pub(crate) fn parse_request<R: DeserializeOwned>(request: &Envelope) -> ParsedRequest<R> {
+    let header = request.header;
+    match (request.message(), header.request()) {
+        (Ok(request), _) => ParsedRequest::Valid(request),
+        (Err(_), Err(_)) => ParsedRequest::Ignored,
+        (Err(_), Ok(_)) if header.in_reply_to.is_some() => ParsedRequest::Ignored,
+        (Err(err), Ok(header)) => {
+            let code = if is_unknown_type::<R>(request.message_type()) {
+                ErrorCode::NotSupported
+            } else {
+                ErrorCode::MalformedRequest
+            };
+            ParsedRequest::Invalid(header, code, err.to_string())
+        }
+    }
+}
+
+/// Whether `R` lacks a variant for messages of `message_type`.
+///
+/// Deserializes `R` from nothing but the `type` field, so only the tag itself
+/// can be an unknown variant.
+fn is_unknown_type<R: DeserializeOwned>(message_type: Option<&str>) -> bool {
+    message_type.is_some_and(|message_type| {
+        let tag = MapDeserializer::new(iter::once(("type", message_type)));
+        matches!(R::deserialize(tag), Err(TagError::UnknownVariant))
+    })
+}
+
+/// Why deserializing a payload in [is_unknown_type] failed.
+#[derive(Debug)]
+enum TagError {
+    UnknownVariant,
+    Other,
+}
+
+impl fmt::Display for TagError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Self::UnknownVariant => f.write_str("unknown variant"),
+            Self::Other => f.write_str("invalid payload"),
+        }
+    }
+}
+
+impl std::error::Error for TagError {}
+
+impl de::Error for TagError {
+    fn custom<T: fmt::Display>(_msg: T) -> Self {
+        Self::Other
+    }
+
+    fn unknown_variant(_variant: &str, _expected: &'static [&'static str]) -> Self {
+        Self::UnknownVariant
+    }
+}
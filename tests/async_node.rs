@@ -0,0 +1,98 @@
+mod common;
+
+use std::time::Duration;
+
+use fly_into_the_maelstrom::{async_node::*, sim::*, *};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestPayload {
+    /// Asks `dest` for its id and replies with the answer.
+    Relay { dest: NodeId },
+    /// Replies with `id_ok`.
+    Id,
+    /// Replies after sleeping for `millis` milliseconds.
+    Sleep { millis: u64 },
+    /// Fails with an error reply.
+    Fail,
+}
+
+fn simulation(node_count: usize) -> Simulation {
+    common::simulation(node_count, SimulationOptions::default(), |init, tx| {
+        let id = init.node_id;
+        Box::new(AsyncNode::new(
+            tx,
+            move |ctx, request: Message<RequestPayload>| async move {
+                let header = request.header;
+                match request.payload {
+                    RequestPayload::Relay { dest } => {
+                        let reply = ctx
+                            .call(dest, json!({"type": "id"}), Some(Duration::from_secs(1)))
+                            .await?
+                            .deserialize::<Value>()
+                            .map_err(|err| ErrorPayload {
+                                code: ErrorCode::Crash,
+                                text: err.to_string(),
+                            })?;
+                        ctx.reply(
+                            &header.request()?,
+                            json!({"type": "relay_ok", "id": reply.payload["id"]}),
+                        );
+                    }
+                    RequestPayload::Id => {
+                        ctx.reply(&header.request()?, json!({"type": "id_ok", "id": id}));
+                    }
+                    RequestPayload::Sleep { millis } => {
+                        ctx.sleep(Duration::from_millis(millis)).await;
+                        ctx.reply(&header.request()?, json!({"type": "sleep_ok"}));
+                    }
+                    RequestPayload::Fail => {
+                        return Err(ErrorPayload {
+                            code: ErrorCode::Abort,
+                            text: "failed".to_owned(),
+                        });
+                    }
+                }
+                Ok(())
+            },
+        ))
+    })
+}
+
+#[test]
+fn handlers_await_rpcs() {
+    let mut sim = simulation(2);
+    let [n0, n1] = sim.node_ids()[..] else {
+        panic!("expected two nodes")
+    };
+    let replies = common::exchange(&mut sim, "c1", n0, json!({"type": "relay", "dest": n1}));
+    assert_eq!(replies.unwrap(), [json!({"type": "relay_ok", "id": n1})]);
+}
+
+#[test]
+fn handlers_run_concurrently() {
+    let mut sim = simulation(1);
+    let client = "c1".parse().unwrap();
+    let node = sim.node_ids()[0];
+    let start = sim.now();
+    sim.send(client, node, json!({"type": "sleep", "millis": 500}));
+    let short = sim.send(client, node, json!({"type": "sleep", "millis": 100}));
+    sim.run_until(start + Duration::from_millis(300)).unwrap();
+    let replies = sim.take_client_messages::<Value>(client).unwrap();
+    assert_eq!(replies.len(), 1);
+    assert_eq!(replies[0].header.in_reply_to, Some(short));
+    sim.run_for(Duration::from_millis(300)).unwrap();
+    assert_eq!(sim.take_client_messages::<Value>(client).unwrap().len(), 1);
+}
+
+#[test]
+fn errors_are_replied() {
+    let mut sim = simulation(1);
+    let n0 = sim.node_ids()[0];
+    let replies = common::exchange(&mut sim, "c1", n0, json!({"type": "fail"})).unwrap();
+    assert_eq!(replies[0]["type"], "error");
+    assert_eq!(replies[0]["code"], 14);
+    assert_eq!(replies[0]["text"], "failed");
+}
@@ -0,0 +1,94 @@
+//! Helpers shared by the integration tests.
+
+// Not every test uses every helper.
+#![allow(dead_code)]
+
+use std::io::{BufRead, Write};
+
+use fly_into_the_maelstrom::{sim::*, transport::Transport, *};
+use serde_json::{json, Value};
+
+/// The `init` message for a single node `n0`.
+pub const INIT: &str = r#"{"src":"c0","dest":"n0","body":{"type":"init","msg_id":1,"node_id":"n0","node_ids":["n0"]}}"#;
+
+/// An `echo` request from `c1` to `n0` with message id 2.
+pub const ECHO: &str = r#"{"src":"c1","dest":"n0","body":{"type":"echo","msg_id":2,"echo":"hi"}}"#;
+
+/// An `echo` request from `c1` to `n0`.
+pub fn echo_request(msg_id: u64) -> String {
+    format!(r#"{{"src":"c1","dest":"n0","body":{{"type":"echo","msg_id":{msg_id},"echo":"hi"}}}}"#)
+}
+
+/// Behaves like the `echo` binary, optionally shouting.
+pub struct EchoNode {
+    pub tx: MessageTransmitter<Value>,
+    pub shout: bool,
+}
+
+impl EchoNode {
+    pub fn new(tx: MessageTransmitter<Value>) -> Self {
+        Self { tx, shout: false }
+    }
+}
+
+impl NodeState for EchoNode {
+    fn handle(&mut self, request: &str) -> Transition {
+        let Message { header, payload } = deserialize_message::<Value>(request)?;
+        let mut echo = payload["echo"].as_str().unwrap_or_default().to_owned();
+        if self.shout {
+            echo = echo.to_uppercase();
+        }
+        self.tx
+            .reply(&header.request()?, json!({"type": "echo_ok", "echo": echo}));
+        Ok(None)
+    }
+}
+
+/// Creates a simulation of `node_count` nodes made by `make_node`.
+pub fn simulation<P: 'static>(
+    node_count: usize,
+    options: SimulationOptions,
+    make_node: impl Fn(InitPayload, MessageTransmitter<P>) -> Box<dyn NodeState> + 'static,
+) -> Simulation {
+    Simulation::new(
+        node_count,
+        options,
+        Box::new(move |init, tx| Ok(make_node(init, tx.into()))),
+    )
+    .unwrap()
+}
+
+/// Sends `body` from `client` to `dest` and returns the payloads of the
+/// replies once the simulation is idle.
+pub fn exchange(
+    sim: &mut Simulation,
+    client: &str,
+    dest: NodeId,
+    body: Value,
+) -> anyhow::Result<Vec<Value>> {
+    let client = client.parse().unwrap();
+    sim.send(client, dest, body);
+    sim.run_until_idle()?;
+    Ok(sim
+        .take_client_messages::<Value>(client)?
+        .into_iter()
+        .map(|message| message.payload)
+        .collect())
+}
+
+/// Writes `inputs` to the node at the other end of `transport` and returns
+/// the first `n` messages it sends back.
+///
+/// The transport is closed afterwards, which stops the node.
+pub fn exchange_lines(transport: impl Transport, inputs: &[&str], n: usize) -> Vec<Value> {
+    let (reader, mut writer) = transport.split().unwrap();
+    for input in inputs {
+        writeln!(writer, "{input}").unwrap();
+    }
+    writer.flush().unwrap();
+    reader
+        .lines()
+        .take(n)
+        .map(|line| serde_json::from_str(&line.unwrap()).unwrap())
+        .collect()
+}
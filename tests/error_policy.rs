@@ -0,0 +1,93 @@
+mod common;
+
+use anyhow::bail;
+use fly_into_the_maelstrom::{sim::*, *};
+use serde::Deserialize;
+use serde_json::{json, Value};
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum Request {
+    Count,
+    Fail,
+}
+
+/// Counts `count` requests and fails on `fail` requests and anything it
+/// can't deserialize.
+struct CountingNode {
+    tx: MessageTransmitter<Value>,
+    count: u64,
+}
+
+impl NodeState for CountingNode {
+    fn handle(&mut self, request: &str) -> Transition {
+        let Message { header, payload } = deserialize_message::<Request>(request)?;
+        match payload {
+            Request::Count => {
+                self.count += 1;
+                let count = self.count;
+                self.tx.reply(
+                    &header.request()?,
+                    json!({"type": "count_ok", "count": count}),
+                );
+                Ok(None)
+            }
+            Request::Fail => bail!("failure"),
+        }
+    }
+}
+
+fn simulation(error_policy: ErrorPolicy) -> Simulation {
+    let options = SimulationOptions {
+        error_policy,
+        ..Default::default()
+    };
+    common::simulation(1, options, |_, tx| Box::new(CountingNode { tx, count: 0 }))
+}
+
+fn send(sim: &mut Simulation, body: Value) -> anyhow::Result<Vec<Value>> {
+    let n0 = sim.node_ids()[0];
+    common::exchange(sim, "c1", n0, body)
+}
+
+#[test]
+fn log_policy_keeps_state() {
+    let mut sim = simulation(ErrorPolicy::Log);
+    send(&mut sim, json!({"type": "count"})).unwrap();
+    assert!(send(&mut sim, json!({"type": "fail"})).unwrap().is_empty());
+    let replies = send(&mut sim, json!({"type": "count"})).unwrap();
+    assert_eq!(replies[0]["count"], 2);
+}
+
+#[test]
+fn reply_error_policy_replies_and_keeps_state() {
+    let mut sim = simulation(ErrorPolicy::ReplyError);
+    send(&mut sim, json!({"type": "count"})).unwrap();
+    let replies = send(&mut sim, json!({"type": "fail"})).unwrap();
+    assert_eq!(replies[0]["type"], "error");
+    assert_eq!(replies[0]["code"], 13);
+    assert_eq!(replies[0]["text"], "failure");
+    let replies = send(&mut sim, json!({"type": "count"})).unwrap();
+    assert_eq!(replies[0]["count"], 2);
+}
+
+#[test]
+fn malformed_requests_keep_state() {
+    for error_policy in [ErrorPolicy::Log, ErrorPolicy::ReplyError] {
+        let mut sim = simulation(error_policy);
+        send(&mut sim, json!({"type": "count"})).unwrap();
+        let replies = send(&mut sim, json!({"type": "unknown"})).unwrap();
+        assert_eq!(
+            replies.len(),
+            usize::from(error_policy == ErrorPolicy::ReplyError)
+        );
+        let replies = send(&mut sim, json!({"type": "count"})).unwrap();
+        assert_eq!(replies[0]["count"], 2);
+    }
+}
+
+#[test]
+fn crash_policy_is_fatal() {
+    let mut sim = simulation(ErrorPolicy::Crash);
+    assert!(send(&mut sim, json!({"type": "fail"})).is_err());
+}
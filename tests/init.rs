@@ -0,0 +1,46 @@
+mod common;
+
+use std::thread;
+
+use anyhow::bail;
+use common::{EchoNode, ECHO, INIT};
+use fly_into_the_maelstrom::{transport::*, *};
+use serde_json::Value;
+
+/// Runs a node in memory, sends it `inputs` and returns its first `n`
+/// replies and whether it succeeded.
+fn exchange(
+    after_init: fn() -> AfterInitTransition,
+    inputs: &[&str],
+    n: usize,
+) -> (Vec<Value>, anyhow::Result<()>) {
+    let (node_end, client_end) = MemoryTransport::pair();
+    let node = thread::spawn(move || run_node_on(node_end, NodeOptions::default(), after_init()));
+    let replies = common::exchange_lines(client_end, inputs, n);
+    (replies, node.join().unwrap())
+}
+
+#[test]
+fn messages_before_init_are_handled_after_init() {
+    let (replies, result) = exchange(
+        || Box::new(|_, tx| Ok(Box::new(EchoNode::new(tx.into())))),
+        &[ECHO, INIT, INIT],
+        3,
+    );
+    result.unwrap();
+    assert_eq!(replies[0]["body"]["type"], "init_ok");
+    assert_eq!(replies[1]["body"]["echo"], "hi");
+    assert_eq!(replies[2]["body"]["type"], "error");
+    assert_eq!(replies[2]["body"]["in_reply_to"], 1);
+}
+
+#[test]
+fn failed_initialization_is_reported() {
+    let (replies, result) = exchange(|| Box::new(|_, _| bail!("missing setting")), &[INIT], 1);
+    assert!(format!("{:#}", result.unwrap_err()).contains("missing setting"));
+    assert_eq!(replies[0]["body"]["type"], "error");
+    assert_eq!(
+        replies[0]["body"]["text"],
+        "failed to initialize node: missing setting"
+    );
+}
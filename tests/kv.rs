@@ -0,0 +1,104 @@
+mod common;
+
+use std::collections::HashMap;
+
+use fly_into_the_maelstrom::{kv::*, sim::*, *};
+use serde_json::{json, Value};
+
+enum PendingCall {
+    Read(KvCall<u64>),
+    Update(KvCall<()>),
+}
+
+/// Forwards `read`, `write` and `cas` requests to `lin-kv` and replies with
+/// the outcome as a string.
+struct ProxyNode {
+    tx: MessageTransmitter<Value>,
+    kv: KvClient<String, u64>,
+    pending: HashMap<MessageId, (MessageHeader, PendingCall)>,
+}
+
+impl NodeState for ProxyNode {
+    fn handle(&mut self, request: &str) -> Transition {
+        let Message { header, payload } = deserialize_message::<Value>(request)?;
+        let key = payload["key"].as_str().unwrap().to_owned();
+        let number = |field: &str| payload[field].as_u64().unwrap();
+        let call = match payload["type"].as_str().unwrap() {
+            "read" => PendingCall::Read(self.kv.read(&mut self.tx, key, None)),
+            "write" => PendingCall::Update(self.kv.write(&mut self.tx, key, number("value"), None)),
+            "cas" => PendingCall::Update(self.kv.cas(
+                &mut self.tx,
+                key,
+                number("from"),
+                number("to"),
+                payload["create_if_not_exists"] == true,
+                None,
+            )),
+            other => panic!("unexpected request {other}"),
+        };
+        let request_id = match &call {
+            PendingCall::Read(call) => call.request_id(),
+            PendingCall::Update(call) => call.request_id(),
+        };
+        self.pending.insert(request_id, (header, call));
+        Ok(None)
+    }
+
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        let (header, call) = self.pending.remove(&response.request_id).unwrap();
+        let result = match call {
+            PendingCall::Read(call) => call.parse(response.result).map(|value| value.to_string()),
+            PendingCall::Update(call) => call.parse(response.result).map(|()| "ok".to_owned()),
+        };
+        let result = result.unwrap_or_else(|err| err.to_string());
+        self.tx.reply(
+            &header.request()?,
+            json!({"type": "result", "result": result}),
+        );
+        Ok(None)
+    }
+}
+
+fn request(sim: &mut Simulation, body: Value) -> String {
+    let n0 = sim.node_ids()[0];
+    let mut replies = common::exchange(sim, "c1", n0, body).unwrap();
+    assert_eq!(replies.len(), 1);
+    replies.remove(0)["result"].as_str().unwrap().to_owned()
+}
+
+#[test]
+fn kv_client_parses_replies() {
+    let mut sim = common::simulation(1, SimulationOptions::default(), |_, tx| {
+        Box::new(ProxyNode {
+            tx,
+            kv: KvClient::lin(),
+            pending: HashMap::new(),
+        })
+    });
+    sim.add_kv_services();
+    let mut request = |body| request(&mut sim, body);
+
+    assert_eq!(
+        request(json!({"type": "read", "key": "k"})),
+        "key does not exist"
+    );
+    assert_eq!(
+        request(json!({"type": "cas", "key": "k", "from": 0, "to": 1})),
+        "key does not exist"
+    );
+    assert_eq!(
+        request(
+            json!({"type": "cas", "key": "k", "from": 0, "to": 1, "create_if_not_exists": true})
+        ),
+        "ok"
+    );
+    assert_eq!(
+        request(json!({"type": "cas", "key": "k", "from": 0, "to": 2})),
+        "precondition failed"
+    );
+    assert_eq!(
+        request(json!({"type": "write", "key": "k", "value": 3})),
+        "ok"
+    );
+    assert_eq!(request(json!({"type": "read", "key": "k"})), "3");
+}
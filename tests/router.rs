@@ -0,0 +1,61 @@
+#![cfg(unix)]
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::TcpStream,
+    process::{Command, Stdio},
+};
+
+use serde_json::Value;
+
+#[test]
+fn router_routes_client_requests_to_nodes() {
+    let mut router = Command::new(env!("CARGO_BIN_EXE_maelstrom-router"))
+        .args(["--bin", env!("CARGO_BIN_EXE_echo"), "--node-count", "2"])
+        .args(["--listen", "127.0.0.1:0"])
+        .env("MAELSTROM_LOG", "info")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::piped())
+        .spawn()
+        .unwrap();
+    let mut stderr = BufReader::new(router.stderr.take().unwrap()).lines();
+    let address = stderr
+        .by_ref()
+        .find_map(|line| Some(line.unwrap().split_once("listening on ")?.1.to_owned()))
+        .unwrap();
+    // Don't let the router block on a full pipe.
+    std::thread::spawn(move || stderr.for_each(drop));
+
+    let stream = TcpStream::connect(address).unwrap();
+    let mut replies = BufReader::new(stream.try_clone().unwrap()).lines();
+    let mut stream = stream;
+    writeln!(
+        stream,
+        r#"{{"src":"me","dest":"n1","body":{{"type":"echo","msg_id":1,"echo":"hi"}}}}"#
+    )
+    .unwrap();
+    let reply: Value = serde_json::from_str(&replies.next().unwrap().unwrap()).unwrap();
+    assert_eq!(reply["src"], "n1");
+    assert_eq!(reply["dest"], "c1");
+    assert_eq!(reply["body"]["echo"], "hi");
+
+    let status = Command::new("kill")
+        .args(["-TERM", &router.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(router.wait().unwrap().success());
+}
+
+#[test]
+fn router_rejects_zero_nodes() {
+    let output = Command::new(env!("CARGO_BIN_EXE_maelstrom-router"))
+        .args(["--bin", env!("CARGO_BIN_EXE_echo"), "--node-count", "0"])
+        .stdin(Stdio::null())
+        .output()
+        .unwrap();
+    assert!(!output.status.success());
+    let stderr = String::from_utf8_lossy(&output.stderr);
+    assert!(stderr.contains("--node-count must be positive"), "{stderr}");
+}
@@ -27,3 +27,44 @@ fn deserialize_custom_body() {
     let MessagePayload::Echo(echo) = message.payload;
     assert_eq!(echo.echo.as_ref(), "Please echo 35");
 }
+
+#[derive(PartialEq, Eq, Clone, Debug, Serialize, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum KVPayload {
+    ReadOk { value: u64 },
+    Error(ErrorPayload),
+}
+
+#[test]
+fn deserialize_error_body() {
+    let json_string = r#"{
+            "src": "seq-kv",
+            "dest": "n1",
+            "body": {
+                "type": "error",
+                "in_reply_to": 3,
+                "code": 20,
+                "text": "key does not exist"
+            }
+        }"#;
+    let message: Message<KVPayload> = serde_json::from_str(json_string).unwrap();
+    let KVPayload::Error(error) = message.payload else {
+        panic!("expected an error payload");
+    };
+    assert_eq!(error.code, ErrorCode::KeyDoesNotExist);
+    assert!(error.code.is_definite());
+    assert_eq!(error.text, "key does not exist");
+}
+
+#[test]
+fn error_codes_roundtrip() {
+    for code in [0, 1, 10, 11, 12, 13, 14, 20, 21, 22, 30, 1000] {
+        let error_code: ErrorCode = serde_json::from_str(&code.to_string()).unwrap();
+        assert_eq!(
+            serde_json::to_string(&error_code).unwrap(),
+            code.to_string()
+        );
+    }
+    assert_eq!(ErrorCode::from(1000), ErrorCode::Other(1000));
+    assert!(!ErrorCode::Crash.is_definite());
+}
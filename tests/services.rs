@@ -0,0 +1,98 @@
+mod common;
+
+use std::time::Duration;
+
+use fly_into_the_maelstrom::{services::*, sim::*, *};
+use serde_json::{json, Value};
+
+fn simulation() -> Simulation {
+    let mut sim = common::simulation(
+        0,
+        SimulationOptions::default(),
+        |_, _: MessageTransmitter<()>| unreachable!("there are no nodes"),
+    );
+    sim.add_kv_services();
+    sim
+}
+
+fn request(sim: &mut Simulation, client: &str, service: NodeId, body: Value) -> Value {
+    let mut replies = common::exchange(sim, client, service, body).unwrap();
+    assert_eq!(replies.len(), 1);
+    replies.remove(0)
+}
+
+#[test]
+fn lin_kv_read_write_cas() {
+    let mut sim = simulation();
+    let mut request = |body| request(&mut sim, "c1", LIN_KV, body);
+
+    let reply = request(json!({"type": "read", "key": "k"}));
+    assert_eq!(reply["type"], "error");
+    assert_eq!(reply["code"], 20);
+
+    let reply = request(json!({"type": "cas", "key": "k", "from": 1, "to": 2}));
+    assert_eq!(reply["code"], 20);
+
+    let reply = request(
+        json!({"type": "cas", "key": "k", "from": 1, "to": 2, "create_if_not_exists": true}),
+    );
+    assert_eq!(reply["type"], "cas_ok");
+
+    let reply = request(json!({"type": "cas", "key": "k", "from": 1, "to": 3}));
+    assert_eq!(reply["code"], 22);
+
+    let reply = request(json!({"type": "write", "key": "k", "value": [1, 2]}));
+    assert_eq!(reply["type"], "write_ok");
+
+    let reply = request(json!({"type": "read", "key": "k"}));
+    assert_eq!(reply, json!({"type": "read_ok", "value": [1, 2]}));
+
+    let reply = request(json!({"type": "delete", "key": "k"}));
+    assert_eq!(reply["code"], 10);
+
+    let reply = request(json!({"type": "write", "key": "k"}));
+    assert_eq!(reply["code"], 12);
+}
+
+#[test]
+fn seq_kv_reads_are_monotonic_but_may_be_stale() {
+    let mut sim = simulation();
+    let mut stale_reads = 0;
+    let mut last_read = 0;
+    for value in 1..=50 {
+        let reply = request(
+            &mut sim,
+            "c1",
+            SEQ_KV,
+            json!({"type": "write", "key": "k", "value": value}),
+        );
+        assert_eq!(reply["type"], "write_ok");
+
+        let reply = request(&mut sim, "c2", SEQ_KV, json!({"type": "read", "key": "k"}));
+        let read = reply["value"].as_u64().unwrap_or_default();
+        assert!(read >= last_read, "reads went back in time");
+        if read < value {
+            stale_reads += 1;
+        }
+        last_read = read;
+    }
+    assert!(stale_reads > 0);
+
+    // A client always observes its own writes.
+    let reply = request(&mut sim, "c1", SEQ_KV, json!({"type": "read", "key": "k"}));
+    assert_eq!(reply["value"], 50);
+}
+
+#[test]
+fn lww_kv_converges() {
+    let mut sim = simulation();
+    for value in 1..=10 {
+        let body = json!({"type": "write", "key": "k", "value": value});
+        request(&mut sim, "c1", LWW_KV, body);
+    }
+    sim.run_for(Duration::from_millis(100)).unwrap();
+    for _ in 0..10 {
+        let reply = request(&mut sim, "c2", LWW_KV, json!({"type": "read", "key": "k"}));
+        assert_eq!(reply["value"], 10);
+    }
+}
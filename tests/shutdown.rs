@@ -0,0 +1,99 @@
+mod common;
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    process::{Command, Stdio},
+};
+
+use common::{echo_request, INIT};
+use fly_into_the_maelstrom::{sim::*, *};
+use serde_json::{json, Value};
+
+#[test]
+fn eof_flushes_all_replies_and_exits_successfully() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "{INIT}").unwrap();
+    for msg_id in 2..500 {
+        writeln!(stdin, "{}", echo_request(msg_id)).unwrap();
+    }
+    drop(stdin);
+
+    let output = child.wait_with_output().unwrap();
+    assert!(output.status.success());
+    assert_eq!(output.stdout.lines().count(), 499);
+}
+
+#[test]
+fn closed_stdout_exits_successfully() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    drop(child.stdout.take());
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "{INIT}").unwrap();
+    // STDIN stays open, the node has to notice the broken pipe on its own.
+    assert!(child.wait().unwrap().success());
+}
+
+#[cfg(unix)]
+#[test]
+fn sigterm_exits_successfully() {
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .stdin(Stdio::piped())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "{INIT}").unwrap();
+    let mut stdout = BufReader::new(child.stdout.take().unwrap());
+    let mut line = String::new();
+    stdout.read_line(&mut line).unwrap();
+    assert!(line.contains("init_ok"));
+
+    let status = Command::new("kill")
+        .args(["-TERM", &child.id().to_string()])
+        .status()
+        .unwrap();
+    assert!(status.success());
+    assert!(child.wait().unwrap().success());
+}
+
+struct GoodbyeNode {
+    tx: MessageTransmitter<Value>,
+}
+
+impl NodeState for GoodbyeNode {
+    fn handle(&mut self, _request: &str) -> Transition {
+        Ok(None)
+    }
+
+    fn on_shutdown(&mut self) {
+        let client = "c1".parse().unwrap();
+        self.tx.send(client, json!({"type": "goodbye"}));
+    }
+}
+
+#[test]
+fn on_shutdown_can_send_messages() {
+    let mut sim = common::simulation(2, SimulationOptions::default(), |_, tx| {
+        Box::new(GoodbyeNode { tx })
+    });
+    sim.shutdown().unwrap();
+    let messages = sim
+        .take_client_messages::<Value>("c1".parse().unwrap())
+        .unwrap();
+    assert_eq!(messages.len(), 2);
+    assert!(messages
+        .iter()
+        .all(|message| message.payload["type"] == "goodbye"));
+}
@@ -0,0 +1,229 @@
+mod common;
+
+use std::{
+    collections::HashMap,
+    time::{Duration, Instant},
+};
+
+use fly_into_the_maelstrom::{sim::*, *};
+use serde_json::{json, Value};
+
+/// Relays `relay` requests to `n1` as `echo` requests, which `n1` ignores if
+/// it is `silent`.
+struct RelayNode {
+    silent: bool,
+    tx: MessageTransmitter<Value>,
+    pending: HashMap<MessageId, RequestHeader>,
+}
+
+impl NodeState for RelayNode {
+    fn handle(&mut self, request: &str) -> Transition {
+        let Message { header, payload } = deserialize_message::<Value>(request)?;
+        match payload["type"].as_str() {
+            Some("relay") => {
+                let dest = "n1".parse().unwrap();
+                let echo = json!({"type": "echo", "echo": payload["echo"]});
+                let request_id = self.tx.call(dest, echo, Some(Duration::from_millis(500)));
+                self.pending.insert(request_id, header.request()?);
+            }
+            Some("echo") if !self.silent => {
+                let echo = json!({"type": "echo_ok", "echo": payload["echo"]});
+                self.tx.reply(&header.request()?, echo);
+            }
+            _ => (),
+        }
+        Ok(None)
+    }
+
+    fn rpc_reply(&mut self, response: RpcResponse) -> Transition {
+        let header = self.pending.remove(&response.request_id).unwrap();
+        let result = match response.result {
+            Ok(reply) => reply.deserialize::<Value>()?.payload["echo"].clone(),
+            Err(error) => json!(error.code.to_string()),
+        };
+        self.tx
+            .reply(&header, json!({"type": "relay_ok", "result": result}));
+        Ok(None)
+    }
+}
+
+/// Notifies itself every second.
+struct TickingNode {
+    id: NodeId,
+    tx: MessageTransmitter<Value>,
+    next_tick: Instant,
+}
+
+impl NodeState for TickingNode {
+    fn handle(&mut self, _request: &str) -> Transition {
+        Ok(None)
+    }
+
+    fn wake_up(&mut self, _timer: TimerId) -> Transition {
+        self.tx.notify(self.id, json!({"type": "tick"}));
+        self.next_tick += Duration::from_secs(1);
+        Ok(None)
+    }
+
+    fn next_wake_up(&self) -> Option<Instant> {
+        Some(self.next_tick)
+    }
+}
+
+fn relay_simulation(silent: bool, options: SimulationOptions) -> Simulation {
+    common::simulation(2, options, move |init, tx| {
+        Box::new(RelayNode {
+            silent: silent && init.node_id.to_string() == "n1",
+            tx,
+            pending: HashMap::new(),
+        })
+    })
+}
+
+#[test]
+fn rpc_reply_is_routed() {
+    let mut sim = relay_simulation(false, SimulationOptions::default());
+    let n0 = sim.node_ids()[0];
+    let replies = common::exchange(&mut sim, "c1", n0, json!({"type": "relay", "echo": 42}));
+    assert_eq!(
+        replies.unwrap(),
+        [json!({"type": "relay_ok", "result": 42})]
+    );
+}
+
+#[test]
+fn init_replies_go_to_c0() {
+    let mut sim = relay_simulation(false, SimulationOptions::default());
+    let replies = sim
+        .take_client_messages::<Value>("c0".parse().unwrap())
+        .unwrap();
+    let mut srcs: Vec<_> = replies.iter().map(|reply| reply.header.src).collect();
+    srcs.sort();
+    assert_eq!(srcs, sim.node_ids());
+    assert!(replies
+        .iter()
+        .all(|reply| reply.payload["type"] == "init_ok"));
+}
+
+#[test]
+fn rpc_times_out_in_virtual_time() {
+    let mut sim = relay_simulation(true, SimulationOptions::default());
+    let client = "c1".parse().unwrap();
+    let start = sim.now();
+    sim.send(
+        client,
+        "n0".parse().unwrap(),
+        json!({"type": "relay", "echo": 42}),
+    );
+    sim.run_for(Duration::from_millis(499)).unwrap();
+    assert!(sim
+        .take_client_messages::<Value>(client)
+        .unwrap()
+        .is_empty());
+
+    sim.run_for(Duration::from_millis(1)).unwrap();
+    let replies = sim.take_client_messages::<Value>(client).unwrap();
+    assert_eq!(
+        replies[0].payload,
+        json!({"type": "relay_ok", "result": "timeout"})
+    );
+    assert_eq!(sim.now() - start, Duration::from_millis(500));
+}
+
+#[test]
+fn simulation_is_deterministic() {
+    let run = |seed| {
+        let options = SimulationOptions {
+            seed,
+            latency: Latency::Exponential {
+                mean: Duration::from_millis(20),
+            },
+            ..Default::default()
+        };
+        let mut sim = relay_simulation(false, options);
+        let client = "c1".parse().unwrap();
+        for echo in 0..10 {
+            sim.send(
+                client,
+                "n0".parse().unwrap(),
+                json!({"type": "relay", "echo": echo}),
+            );
+        }
+        sim.run_until_idle().unwrap();
+        sim.take_client_messages::<Value>(client)
+            .unwrap()
+            .into_iter()
+            .map(|message| message.payload["result"].clone())
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(run(1), run(1));
+    assert_ne!(run(1), run(2));
+}
+
+#[test]
+fn metrics_count_traffic_and_round_trips() {
+    let options = SimulationOptions {
+        latency: Latency::Constant(Duration::from_millis(10)),
+        answer_metrics_requests: true,
+        ..Default::default()
+    };
+    let mut sim = relay_simulation(false, options);
+    let client = "c1".parse().unwrap();
+    let [n0, n1] = sim.node_ids()[..] else {
+        panic!("expected two nodes")
+    };
+    sim.send(client, n0, json!({"type": "relay", "echo": 42}));
+    sim.run_until_idle().unwrap();
+
+    let metrics = sim.metrics(n0).unwrap();
+    assert_eq!(metrics.received.by_type["relay"], 1);
+    assert_eq!(metrics.received.by_type["echo_ok"], 1);
+    assert_eq!(metrics.sent_by_dest[&n1], 1);
+    assert_eq!(metrics.sent_by_dest[&client], 1);
+    assert_eq!(metrics.rpc_round_trip.count, 1);
+    assert_eq!(metrics.rpc_round_trip.max_us, 20_000);
+
+    sim.take_client_messages::<Value>(client).unwrap();
+    let replies = common::exchange(&mut sim, "c1", n0, json!({"type": "metrics"})).unwrap();
+    assert_eq!(replies[0]["type"], "metrics_ok");
+    let metrics: NodeMetrics = serde_json::from_value(replies[0]["metrics"].clone()).unwrap();
+    // `init_ok`, `echo` and `relay_ok`
+    assert_eq!(metrics.sent.messages, 3);
+}
+
+#[test]
+fn nodes_that_keep_sending_are_never_idle() {
+    let options = SimulationOptions {
+        latency: Latency::Constant(Duration::from_secs(2)),
+        ..Default::default()
+    };
+    let mut sim = common::simulation(1, options, |init, tx| {
+        Box::new(TickingNode {
+            id: init.node_id,
+            tx,
+            next_tick: now(),
+        })
+    });
+    let n0 = sim.node_ids()[0];
+    // Timers only fire while there is traffic.
+    sim.send("c1".parse().unwrap(), n0, json!({"type": "start"}));
+    let start = sim.now();
+    assert!(sim.run_until_idle().is_err());
+    assert!(sim.now() <= start + IDLE_TIME_LIMIT);
+
+    let ticks = |sim: &Simulation| sim.metrics(n0).unwrap().sent.by_type["tick"];
+    let before = ticks(&sim);
+    sim.run_for(Duration::from_secs(10)).unwrap();
+    assert_eq!(ticks(&sim) - before, 10);
+}
+
+#[test]
+fn failed_initialization_is_an_error() {
+    let result = Simulation::new(
+        2,
+        SimulationOptions::default(),
+        Box::new(|_, _| anyhow::bail!("missing setting")),
+    );
+    let error = format!("{:#}", result.err().unwrap());
+    assert!(error.contains("missing setting"), "{error}");
+}
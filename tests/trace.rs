@@ -0,0 +1,76 @@
+mod common;
+
+use std::{
+    fs,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use common::{EchoNode, INIT};
+use fly_into_the_maelstrom::{trace::*, *};
+
+/// Runs the `echo` binary with tracing enabled and returns the trace.
+fn record_echo_trace() -> String {
+    let dir = tempdir("echo");
+    let mut child = Command::new(env!("CARGO_BIN_EXE_echo"))
+        .env("MAELSTROM_TRACE_DIR", &dir)
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .unwrap();
+    let mut stdin = child.stdin.take().unwrap();
+    writeln!(stdin, "{INIT}").unwrap();
+    for msg_id in 2..5 {
+        writeln!(
+            stdin,
+            r#"{{"src":"c1","dest":"n0","body":{{"type":"echo","msg_id":{msg_id},"echo":"hi {msg_id}"}}}}"#
+        )
+        .unwrap();
+    }
+    drop(stdin);
+    assert!(child.wait().unwrap().success());
+    fs::read_to_string(dir.join(format!("{}.jsonl", child.id()))).unwrap()
+}
+
+fn tempdir(name: &str) -> std::path::PathBuf {
+    let dir = std::path::Path::new(env!("CARGO_TARGET_TMPDIR")).join(format!("trace-{name}"));
+    fs::create_dir_all(&dir).unwrap();
+    dir
+}
+
+fn replay_echo(trace: &str, shout: bool) -> ReplayReport {
+    replay(
+        trace.as_bytes(),
+        &NodeOptions::default(),
+        Box::new(move |_, tx| {
+            Ok(Box::new(EchoNode {
+                tx: tx.into(),
+                shout,
+            }))
+        }),
+    )
+    .unwrap()
+}
+
+#[test]
+fn recorded_trace_replays() {
+    let trace = record_echo_trace();
+    let records: Vec<TraceRecord> = trace
+        .lines()
+        .map(|line| serde_json::from_str(line).unwrap())
+        .collect();
+    assert_eq!(records[0].event, TraceEvent::Input(INIT.to_owned()));
+    assert_eq!(records.last().unwrap().event, TraceEvent::Shutdown);
+
+    let report = replay_echo(&trace, false);
+    assert!(report.is_match(), "{report}");
+    assert_eq!(report.actual.len(), 4);
+}
+
+#[test]
+fn replay_reports_first_mismatch() {
+    let report = replay_echo(&record_echo_trace(), true);
+    assert_eq!(report.first_mismatch(), Some(1));
+    assert!(report.to_string().contains("HI 2"), "{report}");
+}
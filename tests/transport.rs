@@ -0,0 +1,43 @@
+mod common;
+
+use std::{net::TcpListener, thread};
+
+use common::{EchoNode, ECHO, INIT};
+use fly_into_the_maelstrom::{transport::*, *};
+
+fn run_echo_node(transport: impl Transport + Send + 'static) -> thread::JoinHandle<()> {
+    thread::spawn(move || {
+        run_node_on(
+            transport,
+            NodeOptions::default(),
+            Box::new(|_, tx| Ok(Box::new(EchoNode::new(tx.into())))),
+        )
+        .unwrap()
+    })
+}
+
+/// Talks to an echo node via the other end of its transport.
+fn exchange_echo(transport: impl Transport) {
+    let replies = common::exchange_lines(transport, &[INIT, ECHO], 2);
+    assert_eq!(replies[0]["body"]["type"], "init_ok");
+    assert_eq!(replies[1]["body"]["echo"], "hi");
+}
+
+#[test]
+fn node_runs_in_memory() {
+    let (node_end, client_end) = MemoryTransport::pair();
+    let node = run_echo_node(node_end);
+    exchange_echo(client_end);
+    // Closing the client's end shuts the node down.
+    node.join().unwrap();
+}
+
+#[test]
+fn node_runs_on_tcp() {
+    let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+    let stream = std::net::TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+    let node = run_echo_node(stream);
+    let (client, _) = listener.accept().unwrap();
+    exchange_echo(client);
+    node.join().unwrap();
+}
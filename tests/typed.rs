@@ -0,0 +1,93 @@
+mod common;
+
+use fly_into_the_maelstrom::{sim::*, *};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum RequestPayload {
+    Echo {
+        echo: String,
+        #[serde(default)]
+        case: Option<Case>,
+    },
+}
+
+#[derive(Clone, Debug, Deserialize)]
+#[serde(rename_all = "snake_case")]
+enum Case {
+    Upper,
+}
+
+#[derive(Clone, Debug, Serialize)]
+#[serde(tag = "type", rename_all = "snake_case")]
+enum ResponsePayload {
+    EchoOk { echo: String },
+}
+
+struct EchoNode {
+    tx: MessageTransmitter<ResponsePayload>,
+}
+
+impl TypedNodeState for EchoNode {
+    type Request = RequestPayload;
+    type Response = ResponsePayload;
+
+    fn transmitter(&mut self) -> &mut MessageTransmitter<ResponsePayload> {
+        &mut self.tx
+    }
+
+    fn handle_request(&mut self, request: Message<RequestPayload>) -> Transition {
+        let Message {
+            header,
+            payload: RequestPayload::Echo { echo, case },
+        } = request;
+        let echo = match case {
+            Some(Case::Upper) => echo.to_uppercase(),
+            None => echo,
+        };
+        self.tx
+            .reply(&header.request()?, ResponsePayload::EchoOk { echo });
+        Ok(None)
+    }
+}
+
+#[test]
+fn invalid_requests_are_answered_with_errors() {
+    let mut sim = common::simulation(1, SimulationOptions::default(), |_, tx| {
+        Box::new(EchoNode { tx })
+    });
+    let node = sim.node_ids()[0];
+    let mut request = |body| common::exchange(&mut sim, "c1", node, body).unwrap();
+
+    assert_eq!(
+        request(json!({"type": "echo", "echo": "hello"})),
+        [json!({"type": "echo_ok", "echo": "hello"})]
+    );
+    assert_eq!(
+        request(json!({"type": "shout", "echo": "hello"}))[0]["code"],
+        10
+    );
+    assert_eq!(request(json!({"type": "echo", "echo": 42}))[0]["code"], 12);
+    // An unknown variant of a field is malformed, not an unknown request.
+    assert_eq!(
+        request(json!({"type": "echo", "echo": "hi", "case": "title"}))[0]["code"],
+        12
+    );
+}
+
+#[test]
+fn invalid_notifications_are_ignored() {
+    let mut sim = common::simulation(1, SimulationOptions::default(), |_, tx| {
+        Box::new(EchoNode { tx })
+    });
+    let client = "c1".parse().unwrap();
+    let node = sim.node_ids()[0];
+    sim.notify(client, node, json!({"type": "shout", "echo": "hello"}));
+    sim.run_until_idle().unwrap();
+    assert!(sim
+        .take_client_messages::<Value>(client)
+        .unwrap()
+        .is_empty());
+}